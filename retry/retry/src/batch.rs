@@ -0,0 +1,64 @@
+use crate::{call_idempotent_method_with_retry, RetryError, RetryableCall};
+use candid::Principal;
+use futures::stream::{self, StreamExt};
+use ic_cdk::call::Response;
+
+/// The outcome of one of the calls made by [`batch_call_idempotent_method_with_retry`].
+#[derive(Debug)]
+pub struct BatchCallResult {
+    /// The callee of the call, if the call knows it (see [`RetryableCall`]).
+    pub canister_id: Option<Principal>,
+    /// The response, or the error with which the call ultimately failed. As with
+    /// [`call_idempotent_method_with_retry`], the error distinguishes between calls that
+    /// definitely failed (`RetryError::CallFailed`) and calls whose status is unknown
+    /// (`RetryError::StatusUnknown`).
+    pub result: Result<Response, RetryError>,
+}
+
+/// Makes a batch of idempotent calls concurrently, retrying each one until instructed otherwise
+///
+/// This is useful for fanning out the same call to many canisters (e.g., pushing configuration
+/// to all shards). Each call is made and retried as with [`call_idempotent_method_with_retry`],
+/// with at most `max_in_flight` calls being worked on at the same time.
+///
+/// # Arguments
+///
+/// * `calls` - The (idempotent) calls to execute and retry if needed
+/// * `max_in_flight` - The maximum number of calls to work on at the same time. Values below 1
+///   are treated as 1.
+/// * `new_stop_trying` - Creates the function that determines when to stop (re)trying a call.
+///   It's invoked once per call, as each call needs its own retry policy; for example,
+///   `|| when_out_of_time_or_stopping(&deadline)` or `|| when_max_retries_reached(3)`.
+///
+/// # Returns
+///
+/// A result for each call, in the same order as `calls`. The function doesn't fail as a whole;
+/// inspect the individual results to find the calls that failed.
+pub async fn batch_call_idempotent_method_with_retry<C, F, P>(
+    calls: Vec<C>,
+    max_in_flight: usize,
+    mut new_stop_trying: F,
+) -> Vec<BatchCallResult>
+where
+    C: RetryableCall,
+    F: FnMut() -> P,
+    P: FnMut() -> bool,
+{
+    let calls: Vec<_> = calls
+        .into_iter()
+        .map(|call| (call, new_stop_trying()))
+        .collect();
+
+    stream::iter(calls)
+        .map(|(call, mut stop_trying)| async move {
+            let canister_id = call.callee().map(|(canister_id, _)| canister_id);
+            let result = call_idempotent_method_with_retry(call, &mut stop_trying).await;
+            BatchCallResult {
+                canister_id,
+                result,
+            }
+        })
+        .buffered(max_in_flight.max(1))
+        .collect()
+        .await
+}
@@ -0,0 +1,122 @@
+use candid::utils::ArgumentEncoder;
+use candid::{CandidType, Principal};
+#[cfg(feature = "use_call_chaos")]
+use ic_call_chaos::{Call as InnerCall, CallFuture as InnerCallFuture};
+#[cfg(not(feature = "use_call_chaos"))]
+use ic_cdk::call::{Call as InnerCall, CallFuture as InnerCallFuture};
+use ic_cdk::call::{CallFailed, OnewayError, Response};
+use std::future::IntoFuture;
+
+/// A call that the retry functions can make, and make again.
+///
+/// This is implemented for `ic_cdk::call::Call`, for `ic_call_chaos::Call` (if the
+/// `use_call_chaos` feature is enabled), and for [`Call`]. The latter two remember the callee and
+/// the method, which the retry functions use to key their metrics and rate limits. Calls built
+/// with `ic_cdk::call::Call` are neither recorded in the metrics nor rate-limited; to get both,
+/// build them with [`Call`] instead, which only requires changing the imports.
+pub trait RetryableCall: Clone + IntoFuture<Output = Result<Response, CallFailed>> {
+    /// The callee and the method of the call, if known.
+    fn callee(&self) -> Option<(Principal, &str)>;
+}
+
+impl RetryableCall for ic_cdk::call::Call<'_, '_> {
+    fn callee(&self) -> Option<(Principal, &str)> {
+        None
+    }
+}
+
+#[cfg(feature = "use_call_chaos")]
+impl RetryableCall for ic_call_chaos::Call<'_, '_> {
+    fn callee(&self) -> Option<(Principal, &str)> {
+        Some((self.canister_id, self.method))
+    }
+}
+
+impl RetryableCall for Call<'_, '_> {
+    fn callee(&self) -> Option<(Principal, &str)> {
+        Some((self.canister_id, self.method))
+    }
+}
+
+/// A wrapper around `ic_cdk::call::Call` (or `ic_call_chaos::Call`, if the `use_call_chaos`
+/// feature is enabled) that remembers the callee and the method.
+///
+/// The retry functions use this information to key their metrics and rate limits (see
+/// [`RetryableCall`]). The interface mirrors `ic_cdk::call::Call`, so switching to it should only
+/// require changing the imports.
+/// See the documentation on `ic_cdk::call::Call` for more details on the individual methods.
+#[derive(Clone, Debug)]
+pub struct Call<'m, 'a> {
+    pub canister_id: Principal,
+    pub method: &'m str,
+    call: InnerCall<'m, 'a>,
+}
+
+impl<'m> Call<'m, '_> {
+    pub fn bounded_wait(canister_id: Principal, method: &'m str) -> Self {
+        Call {
+            canister_id,
+            method,
+            call: InnerCall::bounded_wait(canister_id, method),
+        }
+    }
+
+    pub fn unbounded_wait(canister_id: Principal, method: &'m str) -> Self {
+        Call {
+            canister_id,
+            method,
+            call: InnerCall::unbounded_wait(canister_id, method),
+        }
+    }
+}
+
+impl<'a> Call<'_, 'a> {
+    pub fn with_arg<T: CandidType>(self, arg: &T) -> Self {
+        Self {
+            call: self.call.with_arg(arg),
+            ..self
+        }
+    }
+
+    pub fn with_args<A: ArgumentEncoder>(self, args: &A) -> Self {
+        Self {
+            call: self.call.with_args(args),
+            ..self
+        }
+    }
+
+    pub fn with_raw_args(self, raw_args: &'a [u8]) -> Self {
+        Self {
+            call: self.call.with_raw_args(raw_args),
+            ..self
+        }
+    }
+
+    pub fn with_cycles(mut self, cycles: u128) -> Self {
+        self.call = self.call.with_cycles(cycles);
+        self
+    }
+
+    pub fn change_timeout(mut self, timeout_seconds: u32) -> Self {
+        self.call = self.call.change_timeout(timeout_seconds);
+        self
+    }
+
+    pub fn get_cost(&self) -> u128 {
+        self.call.get_cost()
+    }
+
+    /// Sends the call and ignores the reply.
+    pub fn oneway(&self) -> Result<(), OnewayError> {
+        self.call.oneway()
+    }
+}
+
+impl<'m, 'a> IntoFuture for Call<'m, 'a> {
+    type IntoFuture = InnerCallFuture<'m, 'a>;
+    type Output = <InnerCallFuture<'m, 'a> as std::future::Future>::Output;
+
+    fn into_future(self) -> Self::IntoFuture {
+        self.call.into_future()
+    }
+}
@@ -0,0 +1,101 @@
+use crate::RetryableCall;
+use candid::Principal;
+use ic_cdk::call::{CallErrorExt, CallFailed, RejectCode};
+
+/// What a failed call attempt tells us about the effects of the call on the callee.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FailureClass {
+    /// The callee didn't execute the call, and an immediate retry might succeed.
+    RetryableClean,
+    /// The callee didn't execute the call, and an immediate retry is unlikely to succeed.
+    Clean,
+    /// The call was rejected after reaching the callee. By convention (and always in the case of
+    /// the management canister), the callee made no state changes.
+    Rejected,
+    /// The callee failed while processing the call, possibly after a partial execution. For
+    /// example, the callee may have made some state changes, made downstream calls (committing
+    /// those state changes), and then trapped.
+    PartiallyExecuted,
+    /// The callee may have executed the call fully, partially, or not at all, and may still
+    /// execute it in the future.
+    Unknown,
+}
+
+/// Classifies the failure of a call attempt, based on the reject code and the callee.
+///
+/// | Failure                                          | Class               |
+/// |--------------------------------------------------|---------------------|
+/// | Call not performed (e.g., insufficient cycles)   | `Clean`             |
+/// | `SysFatal`, `DestinationInvalid`                 | `Clean`             |
+/// | `SysTransient`                                   | `RetryableClean`    |
+/// | `SysUnknown`                                     | `Unknown`           |
+/// | `CanisterReject`                                 | `Rejected`          |
+/// | `CanisterError` from the management canister     | `Rejected`          |
+/// | `CanisterError` from other canisters             | `PartiallyExecuted` |
+/// | Unrecognized reject code                         | `Unknown`           |
+///
+/// The classification is the same for bounded-wait and unbounded-wait calls, but the two call
+/// types fail in different ways:
+///
+/// - Only bounded-wait calls fail with `SysUnknown`, which means that the call timed out or that
+///   its response was dropped. The callee may have executed the call, or may still execute it in
+///   the future.
+/// - For unbounded-wait calls, the IC guarantees a response, which is either the callee's
+///   response or a system-generated reject. However, the callee may still have made some state
+///   changes and then failed, e.g., by trapping after a downstream call, or by getting
+///   uninstalled or stopped with the call context still open. Such partial executions are the
+///   only source of uncertainty for unbounded-wait calls.
+///
+/// Note that partial executions are possible for bounded-wait calls as well, unless the callee
+/// is the management canister, which executes its methods atomically.
+///
+/// If the call doesn't know its callee (see [`RetryableCall`]), the table above can't tell the
+/// management canister apart from other canisters. Such failures are then classified only based
+/// on whether they are clean and immediately retryable (see `ic_cdk::call::CallErrorExt`), and
+/// both `CanisterReject` and `CanisterError` are classified as `Rejected`.
+///
+/// Also note that we follow the convention that canisters explicitly reject calls only before
+/// making any state changes. This is not enforced by the system, so if you call canisters that
+/// don't follow the convention, treat `Rejected` like `PartiallyExecuted`.
+pub fn classify_failure<C: RetryableCall>(call: &C, error: &CallFailed) -> FailureClass {
+    let Some((callee, _)) = call.callee() else {
+        return classify_without_callee(error);
+    };
+    let rejection = match error {
+        CallFailed::InsufficientLiquidCycleBalance(_) | CallFailed::CallPerformFailed(_) => {
+            return FailureClass::Clean
+        }
+        CallFailed::CallRejected(rejection) => rejection,
+    };
+    let callee_is_management_canister = callee == Principal::management_canister();
+    match rejection.reject_code() {
+        Ok(RejectCode::SysFatal) | Ok(RejectCode::DestinationInvalid) => FailureClass::Clean,
+        Ok(RejectCode::SysTransient) => FailureClass::RetryableClean,
+        Ok(RejectCode::CanisterReject) => FailureClass::Rejected,
+        Ok(RejectCode::CanisterError) if callee_is_management_canister => FailureClass::Rejected,
+        Ok(RejectCode::CanisterError) => FailureClass::PartiallyExecuted,
+        // The call timed out or the response was dropped
+        Ok(RejectCode::SysUnknown) => FailureClass::Unknown,
+        Err(_) => FailureClass::Unknown,
+    }
+}
+
+fn classify_without_callee(error: &CallFailed) -> FailureClass {
+    if !error.is_immediately_retryable() {
+        match error {
+            CallFailed::CallRejected(rejection)
+                if matches!(
+                    rejection.reject_code(),
+                    Ok(RejectCode::CanisterReject) | Ok(RejectCode::CanisterError)
+                ) =>
+            {
+                FailureClass::Rejected
+            }
+            _ => FailureClass::Clean,
+        }
+    } else if !error.is_clean_reject() {
+        FailureClass::Unknown
+    } else {
+        FailureClass::RetryableClean
+    }
+}
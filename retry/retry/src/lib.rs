@@ -11,15 +11,17 @@
 //! # Features
 //!
 //! - Support for both idempotent and non-idempotent calls
+//! - Concurrent batches of idempotent calls with per-call results
 //! - Configurable retry policies with deadlines
 //! - Detailed error reporting
+//! - Per-callee and per-method metrics in the Prometheus text format (see the [`metrics`] module)
+//! - Per-callee rate limits on the attempts (see the [`rate_limit`] module)
 //!
 //! # Examples
 //!
 //! ```rust
-//! use ic_call_retry::{call_idempotent_method_with_retry, when_out_of_time_or_stopping, Deadline};
+//! use ic_call_retry::{call_idempotent_method_with_retry, when_out_of_time_or_stopping, Call, Deadline};
 //! use ic_cdk::api::time;
-//! use ic_cdk::call::Call;
 //!
 //! async fn example_retry_call() -> Result<(), String> {
 //!     // Set a deadline 5 seconds in the future
@@ -55,12 +57,20 @@
 //! - A stopping-based deadline (`Deadline::Stopping`)
 //! - A maximum number of retries (`max_retries`)
 
-#[cfg(feature = "use_call_chaos")]
-use ic_call_chaos::Call;
-use ic_cdk::api::{canister_status, time, CanisterStatusCode};
-#[cfg(not(feature = "use_call_chaos"))]
-use ic_cdk::call::Call;
+mod batch;
+mod call;
+mod classify;
+pub mod metrics;
+pub mod rate_limit;
+
+pub use batch::{batch_call_idempotent_method_with_retry, BatchCallResult};
+pub use call::{Call, RetryableCall};
+use candid::Principal;
+pub use classify::{classify_failure, FailureClass};
+use ic_cdk::api::{canister_self, canister_status, time, CanisterStatusCode};
 use ic_cdk::call::{CallErrorExt, CallFailed, Response};
+use ic_cdk::management_canister::CanisterInfoArgs;
+use metrics::CallRecorder;
 
 /// Represents a deadline for retrying calls.
 ///
@@ -86,6 +96,8 @@ pub enum ErrorCause {
     CallFailed(CallFailed),
     /// The retry was abandoned due to the retry policy
     GaveUpRetrying,
+    /// The call was not attempted (again) because it would have exceeded the callee's rate limit
+    RateLimited,
 }
 
 /// An error type for retried calls.
@@ -117,41 +129,95 @@ pub enum RetryError {
 ///
 /// * `Ok(Response)` if the call succeeds
 /// * `Err(RetryError)` if the call fails and cannot be retried
-pub async fn call_idempotent_method_with_retry<'a, 'm, P>(
-    call: Call<'a, 'm>,
+pub async fn call_idempotent_method_with_retry<C, P>(
+    call: C,
     stop_trying: &mut P,
 ) -> Result<Response, RetryError>
 where
+    C: RetryableCall,
     P: FnMut() -> bool,
 {
+    call_idempotent_method_with_retry_per_attempt(|_attempt| call.clone(), stop_trying).await
+}
+
+/// Makes and, in case of failure, retries an idempotent call until instructed otherwise,
+/// building a fresh call for each attempt
+///
+/// This is the same as [`call_idempotent_method_with_retry`], except that the call for each
+/// attempt is built by `make_call`. This allows the arguments to change between attempts in ways
+/// that don't change the effect of the call, for example to pass the attempt number for tracing.
+/// The retry and error semantics are the same as for [`call_idempotent_method_with_retry`].
+///
+/// Note that you must ensure that the calls built for different attempts are idempotent with
+/// respect to each other, i.e., that executing any number of them has the same effect as
+/// executing one of them. This is because an attempt that failed with an unknown status may
+/// still have been executed by the callee. In particular, don't refresh the timestamps or nonces
+/// that the callee uses to deduplicate calls: for example, an ICRC-1 transfer with a fresh
+/// `created_at_time` is not deduplicated against the previous attempts by the ledger, so it may
+/// transfer the funds twice.
+///
+/// # Arguments
+///
+/// * `make_call` - Builds the (idempotent) call for the given attempt, where the first attempt
+///   is number 0
+/// * `stop_trying` - A function that determines when to stop (re)trying the call
+///
+/// # Returns
+///
+/// * `Ok(Response)` if the call succeeds
+/// * `Err(RetryError)` if the call fails and cannot be retried
+pub async fn call_idempotent_method_with_retry_per_attempt<C, F, P>(
+    mut make_call: F,
+    stop_trying: &mut P,
+) -> Result<Response, RetryError>
+where
+    C: RetryableCall,
+    F: FnMut(u32) -> C,
+    P: FnMut() -> bool,
+{
+    let mut attempt = 0;
+    let mut call = make_call(attempt);
+    let mut recorder = CallRecorder::new(&call);
     let mut no_unknown_results = true;
 
-    loop {
+    let result = loop {
         if stop_trying() {
-            return Err(if no_unknown_results {
+            break Err(if no_unknown_results {
                 RetryError::CallFailed(ErrorCause::GaveUpRetrying)
             } else {
                 RetryError::StatusUnknown(ErrorCause::GaveUpRetrying)
             });
         }
 
-        match call.clone().await {
-            Ok(result) => return Ok(result),
+        let callee = call.callee().map(|(canister_id, _)| canister_id);
+        let _permit = match rate_limit::acquire(callee, stop_trying).await {
+            Ok(permit) => permit,
+            Err(cause) if no_unknown_results => break Err(RetryError::CallFailed(cause)),
+            Err(cause) => break Err(RetryError::StatusUnknown(cause)),
+        };
+        recorder.on_attempt();
+        let attempt_result = call.await;
+        attempt += 1;
+        match attempt_result {
+            Ok(result) => break Ok(result),
             Err(e) if !e.is_immediately_retryable() => {
                 if no_unknown_results {
-                    return Err(RetryError::CallFailed(ErrorCause::CallFailed(e)));
+                    break Err(RetryError::CallFailed(ErrorCause::CallFailed(e)));
                 } else {
-                    return Err(RetryError::StatusUnknown(ErrorCause::CallFailed(e)));
+                    break Err(RetryError::StatusUnknown(ErrorCause::CallFailed(e)));
                 }
             }
             Err(e) if !e.is_clean_reject() => {
                 no_unknown_results = false;
-                continue;
             }
             // The only remaining option is a non-sync SysTransient => retry
-            Err(_e) => continue,
+            Err(_e) => (),
         }
-    }
+        call = make_call(attempt);
+    };
+
+    recorder.finish(&result);
+    result
 }
 
 /// Makes and, in case of failure, retries a non-idempotent call until instructed otherwise
@@ -163,6 +229,12 @@ where
 /// - A non-retryable error occurs
 /// - An error occurs where we cannot determine the final status of the call
 ///
+/// Failures are classified with [`classify_failure`]. Only clean transient failures
+/// (`FailureClass::RetryableClean`) are retried. Possible partial executions and unknown outcomes
+/// result in `RetryError::StatusUnknown`. For bounded-wait calls, this is typically a timeout
+/// (`SysUnknown`); for unbounded-wait calls, it's typically a `CanisterError` from a callee that
+/// failed after a partial execution.
+///
 /// # Arguments
 ///
 /// * `call` - The call to retry
@@ -172,32 +244,43 @@ where
 ///
 /// * `Ok(Response)` if the call succeeds
 /// * `Err(RetryError)` if the call fails and cannot be retried
-pub async fn call_nonidempotent_method_with_retry<'m, 'a, P>(
-    call: Call<'m, 'a>,
+pub async fn call_nonidempotent_method_with_retry<C, P>(
+    call: C,
     stop_trying: &mut P,
 ) -> Result<Response, RetryError>
 where
+    C: RetryableCall,
     P: FnMut() -> bool,
 {
-    loop {
+    let mut recorder = CallRecorder::new(&call);
+    let callee = call.callee().map(|(canister_id, _)| canister_id);
+
+    let result = loop {
         if stop_trying() {
-            return Err(RetryError::CallFailed(ErrorCause::GaveUpRetrying));
+            break Err(RetryError::CallFailed(ErrorCause::GaveUpRetrying));
         }
 
+        let _permit = match rate_limit::acquire(callee, stop_trying).await {
+            Ok(permit) => permit,
+            Err(cause) => break Err(RetryError::CallFailed(cause)),
+        };
+        recorder.on_attempt();
         match call.clone().await {
-            Ok(res) => return Ok(res),
-            Err(e) if !e.is_immediately_retryable() => {
-                return Err(RetryError::CallFailed(ErrorCause::CallFailed(e)))
-            }
-            Err(e) if !e.is_clean_reject() => {
-                return Err(RetryError::StatusUnknown(ErrorCause::CallFailed(e)))
-            }
-            // Non-sync SysTransient => retry
-            Err(_e) => {
-                continue
+            Ok(res) => break Ok(res),
+            Err(e) => match classify_failure(&call, &e) {
+                FailureClass::RetryableClean => continue,
+                FailureClass::Clean | FailureClass::Rejected => {
+                    break Err(RetryError::CallFailed(ErrorCause::CallFailed(e)))
+                }
+                FailureClass::PartiallyExecuted | FailureClass::Unknown => {
+                    break Err(RetryError::StatusUnknown(ErrorCause::CallFailed(e)))
+                }
             },
         }
-    }
+    };
+
+    recorder.finish(&result);
+    result
 }
 
 /// Returns a function that determines whether to stop retrying based on the deadline.
@@ -239,3 +322,18 @@ pub fn when_max_retries_reached(max_retries: u32) -> impl FnMut() -> bool {
         retries > max_retries
     }
 }
+
+/// Lets other messages execute, by making a cheap call to the management canister and waiting
+/// for its response.
+///
+/// Since a call context can't be paused on the IC, this is how to wait for something to change,
+/// e.g., for a rate limit to allow another attempt. The call deliberately bypasses
+/// `ic_call_chaos`, if enabled; it just lets time pass, and its result is ignored.
+pub async fn yield_execution() {
+    let _ = ic_cdk::call::Call::bounded_wait(Principal::management_canister(), "canister_info")
+        .with_arg(CanisterInfoArgs {
+            canister_id: canister_self(),
+            num_requested_changes: None,
+        })
+        .await;
+}
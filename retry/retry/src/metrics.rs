@@ -0,0 +1,299 @@
+//! Metrics about retried calls, kept in a global registry.
+//!
+//! Every call made through [`call_idempotent_method_with_retry`](crate::call_idempotent_method_with_retry)
+//! or [`call_nonidempotent_method_with_retry`](crate::call_nonidempotent_method_with_retry) is
+//! recorded under its callee and method, provided that the call knows them (see
+//! [`RetryableCall`]); calls built with `ic_cdk::call::Call` aren't recorded.
+//! Use [`encode_prometheus`] to expose the metrics, e.g., from a canister's `http_request` handler
+//! serving `/metrics`.
+//!
+//! The following metrics are maintained, each labelled with `callee` and `method`:
+//!
+//! - `ic_call_retry_calls_total`: number of retried calls that have completed
+//! - `ic_call_retry_successes_total`: number of calls that eventually succeeded
+//! - `ic_call_retry_failures_total`: number of calls that failed with a non-retryable error
+//! - `ic_call_retry_giveups_total`: number of calls where the retry policy gave up
+//! - `ic_call_retry_rate_limited_total`: number of calls that failed fast due to a rate limit
+//! - `ic_call_retry_status_unknown_total`: number of calls that ended with `RetryError::StatusUnknown`.
+//!   Note that a give-up can also result in an unknown status, in which case the call is counted
+//!   in both metrics.
+//! - `ic_call_retry_attempts_per_call`: histogram of the number of attempts made per call
+//! - `ic_call_retry_time_to_success_seconds`: histogram of the time from the first attempt until
+//!   a successful response, for calls that eventually succeeded
+
+use crate::{ErrorCause, RetryError, RetryableCall};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_cdk::call::Response;
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+const ATTEMPTS_BUCKETS: &[f64] = &[1.0, 2.0, 3.0, 5.0, 10.0, 20.0, 50.0, 100.0];
+const TIME_TO_SUCCESS_BUCKETS: &[f64] = &[0.1, 0.5, 1.0, 2.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+
+type Registry = BTreeMap<(Principal, String), MethodMetrics>;
+
+lazy_static! {
+    static ref REGISTRY: Mutex<Registry> = Mutex::new(BTreeMap::new());
+}
+
+#[derive(Clone, Debug)]
+struct Histogram {
+    buckets: &'static [f64],
+    /// Non-cumulative counts; `counts[i]` counts the observations that fall into `buckets[i]`,
+    /// and the last element counts the observations above the largest bucket.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Self {
+            buckets,
+            counts: vec![0; buckets.len() + 1],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        let idx = self
+            .buckets
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.buckets.len());
+        self.counts[idx] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Clone, Debug)]
+struct MethodMetrics {
+    calls: u64,
+    successes: u64,
+    failures: u64,
+    giveups: u64,
+    rate_limited: u64,
+    status_unknown: u64,
+    attempts_per_call: Histogram,
+    time_to_success_seconds: Histogram,
+}
+
+impl MethodMetrics {
+    fn new() -> Self {
+        Self {
+            calls: 0,
+            successes: 0,
+            failures: 0,
+            giveups: 0,
+            rate_limited: 0,
+            status_unknown: 0,
+            attempts_per_call: Histogram::new(ATTEMPTS_BUCKETS),
+            time_to_success_seconds: Histogram::new(TIME_TO_SUCCESS_BUCKETS),
+        }
+    }
+}
+
+/// Tracks a single retried call, and records it in the registry once it completes. Calls with
+/// an unknown callee aren't recorded.
+pub(crate) struct CallRecorder {
+    key: Option<(Principal, String)>,
+    first_attempt_at: Option<u64>,
+    attempts: u64,
+}
+
+impl CallRecorder {
+    pub(crate) fn new<C: RetryableCall>(call: &C) -> Self {
+        Self {
+            key: call
+                .callee()
+                .map(|(canister_id, method)| (canister_id, method.to_string())),
+            first_attempt_at: None,
+            attempts: 0,
+        }
+    }
+
+    pub(crate) fn on_attempt(&mut self) {
+        self.first_attempt_at.get_or_insert_with(time);
+        self.attempts += 1;
+    }
+
+    pub(crate) fn finish(self, result: &Result<Response, RetryError>) {
+        let mut registry = REGISTRY
+            .lock()
+            .expect("Couldn't lock the metrics registry when recording a call");
+        let key = match self.key {
+            Some(key) => key,
+            None => return,
+        };
+        let metrics = registry.entry(key).or_insert_with(MethodMetrics::new);
+        metrics.calls += 1;
+        metrics.attempts_per_call.observe(self.attempts as f64);
+        match result {
+            Ok(_) => {
+                metrics.successes += 1;
+                let elapsed_nanos = self
+                    .first_attempt_at
+                    .map_or(0, |started_at| time().saturating_sub(started_at));
+                metrics
+                    .time_to_success_seconds
+                    .observe(elapsed_nanos as f64 / 1_000_000_000.0);
+            }
+            Err(RetryError::CallFailed(cause)) | Err(RetryError::StatusUnknown(cause)) => {
+                match cause {
+                    ErrorCause::GaveUpRetrying => metrics.giveups += 1,
+                    ErrorCause::RateLimited => metrics.rate_limited += 1,
+                    ErrorCause::CallFailed(_) => metrics.failures += 1,
+                }
+                if let Err(RetryError::StatusUnknown(_)) = result {
+                    metrics.status_unknown += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Renders all recorded metrics in the Prometheus text exposition format.
+pub fn encode_prometheus() -> String {
+    let registry = REGISTRY
+        .lock()
+        .expect("Couldn't lock the metrics registry when encoding the metrics");
+    let mut out = String::new();
+
+    encode_counter(
+        &mut out,
+        &registry,
+        "ic_call_retry_calls_total",
+        "Number of retried calls that have completed.",
+        |m| m.calls,
+    );
+    encode_counter(
+        &mut out,
+        &registry,
+        "ic_call_retry_successes_total",
+        "Number of retried calls that eventually succeeded.",
+        |m| m.successes,
+    );
+    encode_counter(
+        &mut out,
+        &registry,
+        "ic_call_retry_failures_total",
+        "Number of retried calls that failed with a non-retryable error.",
+        |m| m.failures,
+    );
+    encode_counter(
+        &mut out,
+        &registry,
+        "ic_call_retry_giveups_total",
+        "Number of retried calls where the retry policy gave up.",
+        |m| m.giveups,
+    );
+    encode_counter(
+        &mut out,
+        &registry,
+        "ic_call_retry_rate_limited_total",
+        "Number of retried calls that failed fast due to a rate limit.",
+        |m| m.rate_limited,
+    );
+    encode_counter(
+        &mut out,
+        &registry,
+        "ic_call_retry_status_unknown_total",
+        "Number of retried calls whose final status is unknown.",
+        |m| m.status_unknown,
+    );
+    encode_histogram(
+        &mut out,
+        &registry,
+        "ic_call_retry_attempts_per_call",
+        "Number of attempts made per retried call.",
+        |m| &m.attempts_per_call,
+    );
+    encode_histogram(
+        &mut out,
+        &registry,
+        "ic_call_retry_time_to_success_seconds",
+        "Time from the first attempt until a successful response.",
+        |m| &m.time_to_success_seconds,
+    );
+
+    out
+}
+
+/// Clears all recorded metrics.
+pub fn reset() {
+    REGISTRY
+        .lock()
+        .expect("Couldn't lock the metrics registry when resetting the metrics")
+        .clear();
+}
+
+fn encode_counter(
+    out: &mut String,
+    registry: &Registry,
+    name: &str,
+    help: &str,
+    value: impl Fn(&MethodMetrics) -> u64,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    for ((canister_id, method), metrics) in registry.iter() {
+        let _ = writeln!(
+            out,
+            "{}{{{}}} {}",
+            name,
+            labels(canister_id, method),
+            value(metrics)
+        );
+    }
+}
+
+fn encode_histogram(
+    out: &mut String,
+    registry: &Registry,
+    name: &str,
+    help: &str,
+    histogram: impl Fn(&MethodMetrics) -> &Histogram,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    for ((canister_id, method), metrics) in registry.iter() {
+        let histogram = histogram(metrics);
+        let labels = labels(canister_id, method);
+        let mut cumulative = 0;
+        for (bound, count) in histogram.buckets.iter().zip(histogram.counts.iter()) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{},le=\"+Inf\"}} {}",
+            name, labels, histogram.count
+        );
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, histogram.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, histogram.count);
+    }
+}
+
+fn labels(canister_id: &Principal, method: &str) -> String {
+    format!(
+        "callee=\"{}\",method=\"{}\"",
+        canister_id,
+        escape_label_value(method)
+    )
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
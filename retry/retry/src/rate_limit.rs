@@ -0,0 +1,254 @@
+//! Rate limits for outgoing calls, keyed by the callee.
+//!
+//! The retry functions consult the limits before each attempt, so that a burst of incoming
+//! messages doesn't amplify into a flood of (retried) calls against a single canister, which
+//! could saturate the caller's output queue and cause even more `SysTransient` rejects.
+//!
+//! A limit consists of an optional cap on the number of attempts in flight to the callee, and an
+//! optional token bucket limiting the rate of attempts. When an attempt would exceed a limit, the
+//! retry function either fails fast with `ErrorCause::RateLimited`, or waits until the attempt
+//! is allowed, as configured by [`OnLimit`].
+//!
+//! Limits are stored in a global registry. Callees without a limit set through
+//! [`set_rate_limit`] use the default limit set through [`set_default_rate_limit`], if any.
+//!
+//! Only calls that know their callee are limited (see [`RetryableCall`](crate::RetryableCall));
+//! calls built with `ic_cdk::call::Call` aren't.
+
+use crate::{yield_execution, ErrorCause};
+use candid::Principal;
+use ic_cdk::api::time;
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// What to do when an attempt would exceed the rate limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnLimit {
+    /// Fail the call immediately, with `ErrorCause::RateLimited`.
+    FailFast,
+    /// Wait until the attempt is allowed.
+    ///
+    /// Since a call context can't be paused on the IC, waiting is done by yielding, i.e., by making
+    /// a cheap call to the management canister and checking the limit again once it returns. The
+    /// retry policy (`stop_trying`) is consulted after each yield, so waiting counts against it;
+    /// in particular, every yield counts as a retry for `when_max_retries_reached`.
+    Wait,
+}
+
+/// A token bucket limiting the rate of attempts.
+///
+/// The bucket starts full. Each attempt takes one token, and tokens are refilled continuously
+/// at the given rate, up to the capacity.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TokenBucket {
+    /// The maximum number of tokens in the bucket, i.e., the largest allowed burst of attempts.
+    pub capacity: u32,
+    /// The number of tokens added to the bucket per second.
+    pub refill_per_second: u32,
+}
+
+/// A limit on the attempts made to a single callee.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimit {
+    /// The maximum number of attempts in flight to the callee at the same time.
+    pub max_in_flight: Option<u32>,
+    /// A token bucket limiting the rate of attempts to the callee.
+    pub token_bucket: Option<TokenBucket>,
+    /// What to do when an attempt would exceed the limit.
+    pub on_limit: OnLimit,
+}
+
+#[derive(Debug)]
+struct CalleeState {
+    in_flight: u32,
+    tokens: f64,
+    last_refill: u64,
+}
+
+impl CalleeState {
+    /// The number of tokens in the bucket at time `now`.
+    fn tokens_at(&self, bucket: &TokenBucket, now: u64) -> f64 {
+        let elapsed_seconds = now.saturating_sub(self.last_refill) as f64 / 1_000_000_000.0;
+        (self.tokens + elapsed_seconds * bucket.refill_per_second as f64)
+            .min(bucket.capacity as f64)
+    }
+
+    /// Whether the state is the same as a fresh one, with no attempts in flight and a full
+    /// bucket, so that it can be dropped.
+    fn is_idle(&self, bucket: Option<&TokenBucket>, now: u64) -> bool {
+        self.in_flight == 0
+            && match bucket {
+                Some(bucket) => self.tokens_at(bucket, now) >= bucket.capacity as f64,
+                None => true,
+            }
+    }
+}
+
+#[derive(Default)]
+struct Limiters {
+    default_limit: Option<RateLimit>,
+    limits: BTreeMap<Principal, RateLimit>,
+    /// The states of the callees with attempts in flight or a bucket that isn't full. The other
+    /// states are dropped, so that the map doesn't grow with every callee ever called.
+    states: BTreeMap<Principal, CalleeState>,
+}
+
+impl Limiters {
+    fn limit(&self, callee: &Principal) -> Option<&RateLimit> {
+        self.limits.get(callee).or(self.default_limit.as_ref())
+    }
+
+    fn bucket(&self, callee: &Principal) -> Option<&TokenBucket> {
+        self.limit(callee)
+            .and_then(|limit| limit.token_bucket.as_ref())
+    }
+
+    /// Drops the states of the idle callees.
+    fn prune(&mut self, now: u64) {
+        let idle: Vec<Principal> = self
+            .states
+            .iter()
+            .filter(|(callee, state)| state.is_idle(self.bucket(callee), now))
+            .map(|(callee, _)| *callee)
+            .collect();
+        for callee in idle {
+            self.states.remove(&callee);
+        }
+    }
+}
+
+lazy_static! {
+    static ref LIMITERS: Mutex<Limiters> = Mutex::new(Limiters::default());
+}
+
+/// Sets the rate limit for calls to the given callee, replacing any previous one.
+pub fn set_rate_limit(callee: Principal, limit: RateLimit) {
+    let mut limiters = LIMITERS
+        .lock()
+        .expect("Couldn't lock the rate limiters when setting a rate limit");
+    limiters.limits.insert(callee, limit);
+}
+
+/// Removes the rate limit for calls to the given callee. The default limit, if any, applies again.
+pub fn clear_rate_limit(callee: Principal) {
+    let mut limiters = LIMITERS
+        .lock()
+        .expect("Couldn't lock the rate limiters when clearing a rate limit");
+    limiters.limits.remove(&callee);
+}
+
+/// Sets the rate limit for calls to callees that don't have a limit of their own.
+///
+/// Note that the limit applies to each such callee separately.
+pub fn set_default_rate_limit(limit: Option<RateLimit>) {
+    let mut limiters = LIMITERS
+        .lock()
+        .expect("Couldn't lock the rate limiters when setting the default rate limit");
+    limiters.default_limit = limit;
+}
+
+/// Marks an attempt to a callee as in flight. The attempt stops counting against the
+/// callee's in-flight limit once the permit is dropped.
+pub(crate) struct Permit {
+    callee: Option<Principal>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(callee) = self.callee {
+            let mut limiters = LIMITERS
+                .lock()
+                .expect("Couldn't lock the rate limiters when releasing a permit");
+            if let Some(state) = limiters.states.get_mut(&callee) {
+                state.in_flight = state.in_flight.saturating_sub(1);
+            }
+            let now = time();
+            let bucket = limiters.bucket(&callee);
+            if limiters
+                .states
+                .get(&callee)
+                .is_some_and(|state| state.is_idle(bucket, now))
+            {
+                limiters.states.remove(&callee);
+            }
+        }
+    }
+}
+
+fn try_acquire(callee: Principal) -> Result<Permit, OnLimit> {
+    let mut limiters = LIMITERS
+        .lock()
+        .expect("Couldn't lock the rate limiters when acquiring a permit");
+    let limit = match limiters.limit(&callee) {
+        Some(limit) => limit.clone(),
+        None => return Ok(Permit { callee: None }),
+    };
+
+    let now = time();
+    // Only add a state after dropping the idle ones, which bounds the number of states by the
+    // number of callees that aren't idle
+    if !limiters.states.contains_key(&callee) {
+        limiters.prune(now);
+    }
+    let state = limiters
+        .states
+        .entry(callee)
+        .or_insert_with(|| CalleeState {
+            in_flight: 0,
+            tokens: limit
+                .token_bucket
+                .as_ref()
+                .map_or(0.0, |bucket| bucket.capacity as f64),
+            last_refill: now,
+        });
+
+    if let Some(max_in_flight) = limit.max_in_flight {
+        if state.in_flight >= max_in_flight {
+            return Err(limit.on_limit);
+        }
+    }
+    if let Some(bucket) = &limit.token_bucket {
+        state.tokens = state.tokens_at(bucket, now);
+        state.last_refill = now;
+        if state.tokens < 1.0 {
+            return Err(limit.on_limit);
+        }
+        state.tokens -= 1.0;
+    }
+
+    state.in_flight += 1;
+    Ok(Permit {
+        callee: Some(callee),
+    })
+}
+
+/// Waits until an attempt to the callee is allowed by its rate limit. Attempts to unknown callees
+/// aren't limited.
+///
+/// Returns `ErrorCause::RateLimited` if the limit says to fail fast, and
+/// `ErrorCause::GaveUpRetrying` if `stop_trying` tells us to stop while waiting.
+pub(crate) async fn acquire<P>(
+    callee: Option<Principal>,
+    stop_trying: &mut P,
+) -> Result<Permit, ErrorCause>
+where
+    P: FnMut() -> bool,
+{
+    let callee = match callee {
+        Some(callee) => callee,
+        None => return Ok(Permit { callee: None }),
+    };
+    loop {
+        match try_acquire(callee) {
+            Ok(permit) => return Ok(permit),
+            Err(OnLimit::FailFast) => return Err(ErrorCause::RateLimited),
+            Err(OnLimit::Wait) => {
+                yield_execution().await;
+                if stop_trying() {
+                    return Err(ErrorCause::GaveUpRetrying);
+                }
+            }
+        }
+    }
+}
@@ -1,7 +1,14 @@
+use candid::Principal;
 use ic_call_chaos::{set_policy as call_chaos_set_policy, Call};
-use ic_call_retry::{call_idempotent_method_with_retry, when_out_of_time_or_stopping, Deadline};
+use ic_call_retry::rate_limit::{OnLimit, RateLimit, TokenBucket};
+use ic_call_retry::{
+    batch_call_idempotent_method_with_retry, call_idempotent_method_with_retry,
+    call_idempotent_method_with_retry_per_attempt, call_nonidempotent_method_with_retry,
+    when_out_of_time_or_stopping, Deadline,
+};
 use ic_cdk::api::canister_self;
 use ic_cdk::call::{CallFailed, CallPerformFailed, CallRejected, OnewayError};
+use ic_cdk::management_canister::CanisterStatusArgs;
 use ic_cdk::{query, update};
 use lazy_static::lazy_static;
 use std::collections::HashSet;
@@ -40,6 +47,15 @@ fn non_idempotent() -> u64 {
     *counter
 }
 
+#[update]
+async fn non_idempotent_then_trap() -> u64 {
+    non_idempotent();
+    // Commit the counter increment by making a downstream call, then trap, resulting
+    // in a partial execution
+    let _ = ic_cdk::call::Call::bounded_wait(canister_self(), "get_counter").await;
+    ic_cdk::trap("Trapping after a partial execution");
+}
+
 #[update]
 async fn call_idempotent(id: u64, deadline: u64, use_unbounded_wait: bool) -> Result<u64, String> {
     let call = if use_unbounded_wait {
@@ -62,6 +78,51 @@ async fn call_idempotent(id: u64, deadline: u64, use_unbounded_wait: bool) -> Re
     res
 }
 
+#[update]
+async fn call_idempotent_with_id_per_attempt(base_id: u64, deadline: u64) -> Result<u64, String> {
+    call_idempotent_method_with_retry_per_attempt(
+        |attempt| {
+            let id = base_id + attempt as u64;
+            Call::bounded_wait(canister_self(), "idempotent").with_arg(&id)
+        },
+        &mut when_out_of_time_or_stopping(&Deadline::TimeOrStopping(deadline)),
+    )
+    .await
+    .map(|resp| {
+        resp.candid::<u64>()
+            .expect("Couldn't decode response from idempotent")
+    })
+    .map_err(|e| format!("Error: {:?}", e))
+}
+
+#[update]
+async fn call_idempotent_batch(
+    ids: Vec<u64>,
+    deadline: u64,
+    max_in_flight: u64,
+) -> Vec<Result<u64, String>> {
+    let calls = ids
+        .iter()
+        .map(|id| Call::bounded_wait(canister_self(), "idempotent").with_arg(id))
+        .collect();
+
+    let deadline = Deadline::TimeOrStopping(deadline);
+    batch_call_idempotent_method_with_retry(calls, max_in_flight as usize, || {
+        when_out_of_time_or_stopping(&deadline)
+    })
+    .await
+    .into_iter()
+    .map(|res| {
+        res.result
+            .map(|resp| {
+                resp.candid::<u64>()
+                    .expect("Couldn't decode response from idempotent")
+            })
+            .map_err(|e| format!("Error: {:?}", e))
+    })
+    .collect()
+}
+
 struct DenyAllSynchronously;
 impl ic_call_chaos::Policy for DenyAllSynchronously {
     fn allow(&mut self, _call: &Call) -> Result<(), CallFailed> {
@@ -128,6 +189,73 @@ async fn call_non_idempotent(use_unbounded_wait: bool) -> Result<u64, String> {
     res
 }
 
+#[update]
+fn set_rate_limit(capacity: u32, refill_per_second: u32, fail_fast: bool) {
+    ic_call_retry::rate_limit::set_rate_limit(
+        canister_self(),
+        RateLimit {
+            max_in_flight: None,
+            token_bucket: Some(TokenBucket {
+                capacity,
+                refill_per_second,
+            }),
+            on_limit: if fail_fast {
+                OnLimit::FailFast
+            } else {
+                OnLimit::Wait
+            },
+        },
+    );
+}
+
+#[query]
+fn metrics() -> String {
+    ic_call_retry::metrics::encode_prometheus()
+}
+
+#[update]
+async fn call_non_idempotent_with_retry(
+    method: String,
+    deadline: u64,
+    use_unbounded_wait: bool,
+) -> Result<u64, String> {
+    let call = if use_unbounded_wait {
+        Call::unbounded_wait(canister_self(), &method)
+    } else {
+        Call::bounded_wait(canister_self(), &method)
+    };
+
+    call_nonidempotent_method_with_retry(
+        call,
+        &mut when_out_of_time_or_stopping(&Deadline::TimeOrStopping(deadline)),
+    )
+    .await
+    .map(|resp| {
+        resp.candid::<u64>()
+            .expect("Couldn't decode response from the non-idempotent method")
+    })
+    .map_err(|e| format!("Error: {:?}", e))
+}
+
+/// Asks the management canister for this canister's status through a plain `ic_cdk::call::Call`,
+/// which doesn't tell the retry function who the callee is.
+#[update]
+async fn own_status_with_retry(deadline: u64) -> Result<(), String> {
+    let call =
+        ic_cdk::call::Call::bounded_wait(Principal::management_canister(), "canister_status")
+            .with_arg(&CanisterStatusArgs {
+                canister_id: canister_self(),
+            });
+
+    call_nonidempotent_method_with_retry(
+        call,
+        &mut when_out_of_time_or_stopping(&Deadline::TimeOrStopping(deadline)),
+    )
+    .await
+    .map(|_| ())
+    .map_err(|e| format!("Error: {:?}", e))
+}
+
 #[query]
 fn get_counter() -> u64 {
     let counter = COUNTER
@@ -294,3 +294,365 @@ fn nonidempotent_not_retried_on_sys_unknown() -> Result<(), String> {
 
     Ok(())
 }
+
+fn get_metrics(pic: &PocketIc, canister_id: Principal) -> String {
+    let response = pic
+        .query_call(canister_id, Principal::anonymous(), "metrics", encode_one(()).unwrap())
+        .expect("Failed to call metrics");
+    decode_one(&response).expect("Failed to decode the metrics")
+}
+
+#[test]
+fn metrics_recorded() -> Result<(), String> {
+    let canister_id = install_canister(&PIC);
+    set_policy(&PIC, canister_id, "AllowEveryOther");
+
+    let curr_time = PIC.get_time().as_nanos_since_unix_epoch();
+    let deadline = curr_time + 300_000_000_000; // 5 minutes in the future
+
+    let res = call_idempotent(&PIC, canister_id, 1, deadline, false);
+    assert_eq!(res, Ok(1));
+
+    let metrics = get_metrics(&PIC, canister_id);
+    let labels = format!("callee=\"{}\",method=\"idempotent\"", canister_id);
+    for expected in [
+        format!("ic_call_retry_calls_total{{{}}} 1", labels),
+        format!("ic_call_retry_successes_total{{{}}} 1", labels),
+        format!("ic_call_retry_giveups_total{{{}}} 0", labels),
+        // The first attempt is rejected by the policy, the second one goes through
+        format!("ic_call_retry_attempts_per_call_sum{{{}}} 2", labels),
+    ] {
+        assert!(
+            metrics.contains(&expected),
+            "Expected the metrics to contain {}, got:\n{}",
+            expected,
+            metrics
+        );
+    }
+
+    Ok(())
+}
+
+fn set_rate_limit(
+    pic: &PocketIc,
+    canister_id: Principal,
+    capacity: u32,
+    refill_per_second: u32,
+    fail_fast: bool,
+) {
+    pic.update_call(
+        canister_id,
+        Principal::anonymous(),
+        "set_rate_limit",
+        encode_args((capacity, refill_per_second, fail_fast)).expect("Couldn't encode args"),
+    )
+    .expect("Failed to set the rate limit");
+}
+
+#[test]
+fn rate_limit_fails_fast() -> Result<(), String> {
+    let canister_id = install_canister(&PIC);
+    // A single token that never gets refilled
+    set_rate_limit(&PIC, canister_id, 1, 0, true);
+
+    let curr_time = PIC.get_time().as_nanos_since_unix_epoch();
+    let deadline = curr_time + 300_000_000_000; // 5 minutes in the future
+
+    let res = call_idempotent(&PIC, canister_id, 1, deadline, false);
+    assert_eq!(res, Ok(1), "The first call should get the only token");
+
+    let res = call_idempotent(&PIC, canister_id, 2, deadline, false);
+    match res {
+        Err(e) => assert!(e.contains("RateLimited"), "Unexpected error: {}", e),
+        Ok(_) => panic!("Expected the second call to be rate limited"),
+    }
+    assert!(
+        PIC.get_time().as_nanos_since_unix_epoch() < deadline,
+        "Expected the call to fail fast, not because the deadline expired"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn rate_limit_waits_for_tokens() -> Result<(), String> {
+    let canister_id = install_canister(&PIC);
+    // A single token, refilled once per second
+    set_rate_limit(&PIC, canister_id, 1, 1, false);
+
+    let curr_time = PIC.get_time().as_nanos_since_unix_epoch();
+    let deadline = curr_time + 300_000_000_000; // 5 minutes in the future
+
+    let res = call_idempotent(&PIC, canister_id, 1, deadline, false);
+    assert_eq!(res, Ok(1), "The first call should get the only token");
+
+    let request_id = PIC
+        .submit_call(
+            canister_id,
+            Principal::anonymous(),
+            "call_idempotent",
+            encode_args((2_u64, deadline, false)).expect("Couldn't encode args"),
+        )
+        .expect("Failed to call canister");
+    PIC.tick();
+    // Let the bucket refill
+    PIC.advance_time(std::time::Duration::from_secs(2));
+
+    let response: Result<u64, String> =
+        decode_one(&PIC.await_call(request_id).expect("Failed to await call"))
+            .expect("Failed to decode response");
+    assert_eq!(response, Ok(2), "The second call should go through after waiting");
+
+    Ok(())
+}
+
+fn call_non_idempotent_with_retry(
+    pic: &PocketIc,
+    canister_id: Principal,
+    method: &str,
+    deadline: u64,
+    use_unbounded_wait: bool,
+) -> Result<u64, String> {
+    let response = pic
+        .update_call(
+            canister_id,
+            Principal::anonymous(),
+            "call_non_idempotent_with_retry",
+            encode_args((method, deadline, use_unbounded_wait)).expect("Couldn't encode args"),
+        )
+        .expect("Failed to call canister");
+    decode_one(&response).expect("Failed to decode response")
+}
+
+fn get_counter(pic: &PocketIc, canister_id: Principal) -> u64 {
+    let response = pic
+        .query_call(canister_id, Principal::anonymous(), "get_counter", encode_one(()).unwrap())
+        .expect("Failed to call get_counter");
+    decode_one(&response).expect("Failed to decode the counter")
+}
+
+#[test]
+fn nonidempotent_with_retry_succeeds_for_both_call_types() -> Result<(), String> {
+    for use_unbounded_wait in [false, true] {
+        let canister_id = install_canister(&PIC);
+        set_policy(&PIC, canister_id, "AllowEveryOther");
+        let curr_time = PIC.get_time().as_nanos_since_unix_epoch();
+        let deadline = curr_time + 300_000_000_000; // 5 minutes in the future
+
+        let res = call_non_idempotent_with_retry(
+            &PIC,
+            canister_id,
+            "non_idempotent",
+            deadline,
+            use_unbounded_wait,
+        );
+
+        assert_eq!(
+            res,
+            Ok(1),
+            "Failed with use_unbounded_wait = {}",
+            use_unbounded_wait
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn nonidempotent_partial_execution_is_status_unknown() -> Result<(), String> {
+    for use_unbounded_wait in [false, true] {
+        let canister_id = install_canister(&PIC);
+        let curr_time = PIC.get_time().as_nanos_since_unix_epoch();
+        let deadline = curr_time + 300_000_000_000; // 5 minutes in the future
+
+        let res = call_non_idempotent_with_retry(
+            &PIC,
+            canister_id,
+            "non_idempotent_then_trap",
+            deadline,
+            use_unbounded_wait,
+        );
+
+        match res {
+            Err(e) => assert!(
+                e.contains("StatusUnknown"),
+                "Expected an unknown status with use_unbounded_wait = {}, got {}",
+                use_unbounded_wait,
+                e
+            ),
+            Ok(_) => panic!("Expected an error with use_unbounded_wait = {}", use_unbounded_wait),
+        }
+        assert_eq!(
+            get_counter(&PIC, canister_id),
+            1,
+            "The partially executed call should not have been retried with use_unbounded_wait = {}",
+            use_unbounded_wait
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn nonidempotent_retry_classifies_timeouts_and_rejects() -> Result<(), String> {
+    // A timeout is only possible for bounded-wait calls
+    let canister_id = install_canister(&PIC);
+    set_policy(&PIC, canister_id, "DenyWithSysUnknown");
+    let curr_time = PIC.get_time().as_nanos_since_unix_epoch();
+    let deadline = curr_time + 300_000_000_000; // 5 minutes in the future
+    let res = call_non_idempotent_with_retry(&PIC, canister_id, "non_idempotent", deadline, false);
+    match res {
+        Err(e) => assert!(e.contains("StatusUnknown"), "Unexpected error: {}", e),
+        Ok(_) => panic!("Expected an error"),
+    }
+
+    // An explicit reject is a definite failure for both call types
+    for use_unbounded_wait in [false, true] {
+        let canister_id = install_canister(&PIC);
+        set_policy(&PIC, canister_id, "DenyWithCanisterReject");
+        let res = call_non_idempotent_with_retry(
+            &PIC,
+            canister_id,
+            "non_idempotent",
+            deadline,
+            use_unbounded_wait,
+        );
+        match res {
+            Err(e) => assert!(
+                e.contains("CallFailed(CallFailed"),
+                "Expected a definite failure with use_unbounded_wait = {}, got {}",
+                use_unbounded_wait,
+                e
+            ),
+            Ok(_) => panic!("Expected an error with use_unbounded_wait = {}", use_unbounded_wait),
+        }
+        assert_eq!(get_counter(&PIC, canister_id), 0);
+    }
+    Ok(())
+}
+
+#[test]
+fn nonidempotent_retry_reports_management_canister_errors_as_definite_without_a_callee() {
+    // The canister isn't its own controller, so the management canister rejects the status
+    // request with a `CanisterError`. The plain `ic_cdk::call::Call` doesn't tell the retry
+    // function that it's talking to the management canister, which must not turn the error into
+    // an unknown outcome.
+    let canister_id = install_canister(&PIC);
+    let curr_time = PIC.get_time().as_nanos_since_unix_epoch();
+    let deadline = curr_time + 300_000_000_000; // 5 minutes in the future
+    let response = PIC
+        .update_call(
+            canister_id,
+            Principal::anonymous(),
+            "own_status_with_retry",
+            encode_one(deadline).expect("Couldn't encode args"),
+        )
+        .expect("Failed to call retry canister");
+    let res: Result<(), String> = decode_one(&response).expect("Failed to decode response");
+    match res {
+        Err(e) => assert!(e.contains("CallFailed(CallFailed"), "Unexpected error: {}", e),
+        Ok(_) => panic!("Expected an error"),
+    }
+}
+
+fn call_idempotent_batch(
+    pic: &PocketIc,
+    canister_id: Principal,
+    ids: Vec<u64>,
+    deadline: u64,
+    max_in_flight: u64,
+) -> Vec<Result<u64, String>> {
+    let response = pic
+        .update_call(
+            canister_id,
+            Principal::anonymous(),
+            "call_idempotent_batch",
+            encode_args((ids, deadline, max_in_flight)).expect("Couldn't encode args"),
+        )
+        .expect("Failed to call canister");
+    decode_one(&response).expect("Failed to decode response")
+}
+
+#[test]
+fn batch_overcomes_intermittent_failures() -> Result<(), String> {
+    let canister_id = install_canister(&PIC);
+    set_policy(&PIC, canister_id, "WithProbability");
+
+    let curr_time = PIC.get_time().as_nanos_since_unix_epoch();
+    let deadline = curr_time + 300_000_000_000; // 5 minutes in the future
+    const NR_IDS: u64 = 10;
+
+    let results = call_idempotent_batch(&PIC, canister_id, (0..NR_IDS).collect(), deadline, 3);
+
+    assert_eq!(results.len(), NR_IDS as usize);
+    for res in results {
+        match res {
+            Ok(nr_stored) => assert!((1..=NR_IDS).contains(&nr_stored)),
+            Err(e) => panic!("Expected all calls in the batch to succeed, got {}", e),
+        }
+    }
+    // All IDs should have been stored exactly once
+    assert_eq!(
+        call_idempotent(&PIC, canister_id, 0, deadline, false),
+        Ok(NR_IDS)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn batch_reports_failures_per_call() -> Result<(), String> {
+    let canister_id = install_canister(&PIC);
+    set_policy(&PIC, canister_id, "DenyWithCanisterReject");
+
+    let curr_time = PIC.get_time().as_nanos_since_unix_epoch();
+    let deadline = curr_time + 300_000_000_000; // 5 minutes in the future
+
+    let results = call_idempotent_batch(&PIC, canister_id, vec![1, 2, 3], deadline, 2);
+
+    assert_eq!(results.len(), 3);
+    for res in results {
+        match res {
+            Err(e) => assert!(e.contains("CallFailed"), "Unexpected error: {}", e),
+            Ok(_) => panic!("Expected all calls in the batch to fail"),
+        }
+    }
+
+    Ok(())
+}
+
+fn call_idempotent_with_id_per_attempt(
+    pic: &PocketIc,
+    canister_id: Principal,
+    base_id: u64,
+    deadline: u64,
+) -> Result<u64, String> {
+    let response = pic
+        .update_call(
+            canister_id,
+            Principal::anonymous(),
+            "call_idempotent_with_id_per_attempt",
+            encode_args((base_id, deadline)).expect("Couldn't encode args"),
+        )
+        .expect("Failed to call canister");
+    decode_one(&response).expect("Failed to decode response")
+}
+
+#[test]
+fn arguments_regenerated_per_attempt() -> Result<(), String> {
+    let canister_id = install_canister(&PIC);
+    // The first attempt fails, the second one goes through
+    set_policy(&PIC, canister_id, "AllowEveryOther");
+
+    let curr_time = PIC.get_time().as_nanos_since_unix_epoch();
+    let deadline = curr_time + 300_000_000_000; // 5 minutes in the future
+
+    let res = call_idempotent_with_id_per_attempt(&PIC, canister_id, 10, deadline);
+    assert_eq!(res, Ok(1));
+
+    // Only the ID from the second attempt should have been stored
+    let res = call_idempotent(&PIC, canister_id, 11, deadline, false);
+    assert_eq!(res, Ok(1), "Expected the ID of the second attempt to be stored");
+    let res = call_idempotent(&PIC, canister_id, 10, deadline, false);
+    assert_eq!(res, Ok(2), "Expected the ID of the first attempt not to be stored");
+
+    Ok(())
+}
@@ -0,0 +1,146 @@
+use crate::proxy::Target;
+use crate::{CanisterId, ChunkedModule};
+use candid::Principal;
+use ic_call_retry::{call_idempotent_method_with_retry, Call, RetryError};
+use ic_cdk::management_canister::{
+    ChunkHash, ClearChunkStoreArgs, StoredChunksArgs, StoredChunksResult, UploadChunkArgs,
+    UploadChunkResult,
+};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+
+/// The maximum size of a single chunk in a canister's chunk store (1 MiB).
+pub const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Errors returned by `upload_chunks`.
+#[derive(Debug, Clone)]
+pub enum ChunkUploadError {
+    RetryError(RetryError),
+    /// The management canister reported a different hash for an uploaded chunk than the
+    /// one we computed locally.
+    ChunkHashMismatch {
+        expected: Vec<u8>,
+        actual: Vec<u8>,
+    },
+}
+
+impl From<RetryError> for ChunkUploadError {
+    fn from(error: RetryError) -> Self {
+        ChunkUploadError::RetryError(error)
+    }
+}
+
+/// Uploads a WASM module into the chunk store of the given canister, such that it can be
+/// installed with `WasmModule::ChunkedModule`.
+///
+/// Splits the module into chunks of at most `MAX_CHUNK_SIZE` bytes, and uploads the chunks that
+/// aren't already in the store (as reported by `stored_chunks`). The hash of each uploaded chunk
+/// is checked against the hash computed locally. Uses bounded-wait calls under the hood, and
+/// retries any failed calls until the `stop_trying` function returns true. Since uploading a
+/// chunk is idempotent, the upload can also be safely restarted if it fails part-way.
+///
+/// The store canister must be on the same subnet as the canister(s) the module will be
+/// installed on, and the caller must be one of its controllers. Note that chunk stores have a
+/// limited capacity; use `clear_chunk_store` to remove the chunks once they're no longer needed.
+///
+/// # Returns
+/// * `Ok(ChunkedModule)` describing the uploaded module, ready to be passed to `upgrade_canister`.
+/// * `Err(ChunkUploadError)` if the upload failed or its status is unknown.
+pub async fn upload_chunks<P>(
+    store_canister_id: CanisterId,
+    wasm_module: &[u8],
+    stop_trying: &mut P,
+) -> Result<ChunkedModule, ChunkUploadError>
+where
+    P: FnMut() -> bool,
+{
+    upload_chunks_to(Target::direct(store_canister_id), wasm_module, stop_trying).await
+}
+
+/// Uploads the module into the chunk store of `store.id`, relaying the calls through the proxy of
+/// `store`, if any.
+pub(crate) async fn upload_chunks_to<P>(
+    store: Target<'_>,
+    wasm_module: &[u8],
+    stop_trying: &mut P,
+) -> Result<ChunkedModule, ChunkUploadError>
+where
+    P: FnMut() -> bool,
+{
+    let store_canister_id = store.id;
+    let chunk_hashes_list: Vec<Vec<u8>> = wasm_module
+        .chunks(MAX_CHUNK_SIZE)
+        .map(|chunk| Sha256::digest(chunk).to_vec())
+        .collect();
+
+    let stored: StoredChunksResult = call_idempotent_method_with_retry(
+        store.management_call(
+            "stored_chunks",
+            &StoredChunksArgs {
+                canister_id: store_canister_id,
+            },
+        ),
+        stop_trying,
+    )
+    .await?
+    .candid()
+    .unwrap();
+    let mut present: BTreeSet<Vec<u8>> = stored.into_iter().map(|h| h.hash).collect();
+
+    for (chunk, expected) in wasm_module
+        .chunks(MAX_CHUNK_SIZE)
+        .zip(chunk_hashes_list.iter())
+    {
+        if present.contains(expected) {
+            continue;
+        }
+        let args = UploadChunkArgs {
+            canister_id: store_canister_id,
+            chunk: chunk.to_vec(),
+        };
+        let ChunkHash { hash: actual }: UploadChunkResult = call_idempotent_method_with_retry(
+            store.management_call("upload_chunk", &args),
+            stop_trying,
+        )
+        .await?
+        .candid()
+        .unwrap();
+        if actual != *expected {
+            return Err(ChunkUploadError::ChunkHashMismatch {
+                expected: expected.clone(),
+                actual,
+            });
+        }
+        present.insert(actual);
+    }
+
+    Ok(ChunkedModule {
+        wasm_module_hash: Sha256::digest(wasm_module).to_vec(),
+        store_canister_id,
+        chunk_hashes_list,
+    })
+}
+
+/// Removes all chunks from the chunk store of the given canister, with bounded-wait calls
+/// retried until the `stop_trying` function returns true. The caller must be one of the store
+/// canister's controllers; a store controlled only by a `ManagementProxy` has to be cleared by the
+/// proxy itself.
+pub async fn clear_chunk_store<P>(
+    store_canister_id: CanisterId,
+    stop_trying: &mut P,
+) -> Result<(), RetryError>
+where
+    P: FnMut() -> bool,
+{
+    let args = ClearChunkStoreArgs {
+        canister_id: store_canister_id,
+    };
+    let _: () = call_idempotent_method_with_retry(
+        Call::bounded_wait(Principal::management_canister(), "clear_chunk_store").with_arg(&args),
+        stop_trying,
+    )
+    .await?
+    .candid()
+    .unwrap();
+    Ok(())
+}
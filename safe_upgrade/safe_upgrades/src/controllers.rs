@@ -0,0 +1,152 @@
+use crate::proxy::Target;
+use crate::{bounded_wait_canister_info, CanisterId, LifecycleError, LifecycleOperation};
+use candid::{CandidType, Principal};
+use ic_call_retry::{call_nonidempotent_method_with_retry, Call, RetryError};
+use ic_cdk::api::{canister_self, canister_version};
+use ic_management_canister_types::{CanisterSettings, UpdateSettingsArgs};
+use serde::{Deserialize, Serialize};
+
+/// A change to the controllers of a canister, for `change_controllers`.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ControllerChange {
+    /// Controllers to add. Principals that already control the canister are ignored.
+    pub add: Vec<Principal>,
+    /// Controllers to remove. Principals that don't control the canister are ignored.
+    pub remove: Vec<Principal>,
+    /// Whether the change may remove this canister from the controllers. Doing so is refused by
+    /// default, as this canister can't undo the change afterwards.
+    pub allow_removing_self: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum ControllerChangeError {
+    /// The change would remove this canister from the controllers, but `allow_removing_self`
+    /// isn't set. Nothing was changed.
+    WouldRemoveSelf,
+    /// Reading or updating the controllers failed. A `RetryError::StatusUnknown` error at the
+    /// `UpdatingSettings` operation means that we couldn't determine whether the change was
+    /// applied.
+    CallFailed(LifecycleError),
+    /// The controllers changed to something other than what we set, so someone else changed them
+    /// concurrently. Reports the controllers we observed.
+    ConcurrentChangeDetected { controllers: Vec<Principal> },
+}
+
+/// Adds and removes controllers of the target with bounded-wait calls.
+///
+/// Losing the response to a controller change means that we don't know whether we still control
+/// the target, so this function doesn't blindly retry the change. Rather, it:
+/// 1. Reads the current controllers using `canister_info`, which doesn't require being a
+///    controller.
+/// 2. Computes the new controllers from the current ones, and refuses to remove this canister,
+///    unless `allow_removing_self` is set. Returns right away if nothing changes.
+/// 3. Sets the new controllers using `update_settings`. If the outcome of the call is unknown,
+///    re-reads the controllers: if they are the new ones, the change went through; if they are
+///    still the old ones, the change is retried, or reported as `StatusUnknown` if `stop_trying`
+///    says to stop; otherwise, someone else changed them.
+///
+/// Note that there is no protection against someone else changing the controllers between steps
+/// 1 and 3; that change is then overwritten.
+///
+/// # Arguments
+/// * `target_id` - The canister whose controllers to change
+/// * `change` - The controllers to add and remove
+/// * `stop_trying` - A function that determines when to stop (re)trying the calls
+///
+/// # Returns
+/// * `Ok(Vec<Principal>)` with the target's new controllers
+/// * `Err(ControllerChangeError)` otherwise.
+pub async fn change_controllers<P>(
+    target_id: CanisterId,
+    change: &ControllerChange,
+    stop_trying: &mut P,
+) -> Result<Vec<Principal>, ControllerChangeError>
+where
+    P: FnMut() -> bool,
+{
+    if !change.allow_removing_self && change.remove.contains(&canister_self()) {
+        return Err(ControllerChangeError::WouldRemoveSelf);
+    }
+
+    let current = read_controllers(target_id, stop_trying).await?;
+    let mut new: Vec<Principal> = current
+        .iter()
+        .filter(|controller| !change.remove.contains(controller))
+        .copied()
+        .collect();
+    for controller in &change.add {
+        if !new.contains(controller) && !change.remove.contains(controller) {
+            new.push(*controller);
+        }
+    }
+    if same_controllers(&new, &current) {
+        return Ok(current);
+    }
+
+    let args = UpdateSettingsArgs {
+        canister_id: target_id,
+        settings: CanisterSettings {
+            controllers: Some(new.clone()),
+            ..Default::default()
+        },
+        sender_canister_version: Some(canister_version()),
+    };
+    loop {
+        match call_nonidempotent_method_with_retry(
+            Call::bounded_wait(Principal::management_canister(), "update_settings").with_arg(&args),
+            stop_trying,
+        )
+        .await
+        {
+            Ok(_) => return Ok(new),
+            Err(RetryError::StatusUnknown(cause)) => {
+                match read_controllers(target_id, stop_trying).await.ok() {
+                    Some(observed) if same_controllers(&observed, &new) => return Ok(observed),
+                    Some(observed) if !same_controllers(&observed, &current) => {
+                        return Err(ControllerChangeError::ConcurrentChangeDetected {
+                            controllers: observed,
+                        })
+                    }
+                    // The previous attempt may still go through, so giving up now leaves the
+                    // outcome unknown
+                    Some(_) if !stop_trying() => (),
+                    _ => {
+                        return Err(ControllerChangeError::CallFailed(LifecycleError {
+                            operation: LifecycleOperation::UpdatingSettings,
+                            error: RetryError::StatusUnknown(cause),
+                        }))
+                    }
+                }
+            }
+            Err(error) => {
+                return Err(ControllerChangeError::CallFailed(LifecycleError {
+                    operation: LifecycleOperation::UpdatingSettings,
+                    error,
+                }))
+            }
+        }
+    }
+}
+
+async fn read_controllers<P>(
+    target_id: CanisterId,
+    stop_trying: &mut P,
+) -> Result<Vec<Principal>, ControllerChangeError>
+where
+    P: FnMut() -> bool,
+{
+    bounded_wait_canister_info(Target::direct(target_id), Some(0), stop_trying)
+        .await
+        .map(|info| info.controllers)
+        .map_err(|error| {
+            ControllerChangeError::CallFailed(LifecycleError {
+                operation: LifecycleOperation::ObtainingInfo,
+                error,
+            })
+        })
+}
+
+/// Whether the two lists contain the same controllers; the order doesn't matter.
+fn same_controllers(a: &[Principal], b: &[Principal]) -> bool {
+    a.len() == b.len() && a.iter().all(|controller| b.contains(controller))
+}
@@ -0,0 +1,121 @@
+use crate::{UpgradeStage, VersionChangeCheck};
+use candid::{CandidType, Principal};
+use ic_call_retry::RetryableCall;
+use ic_cdk::call::{CallFailed, Response};
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::future::IntoFuture;
+
+/// An event in the progress of an upgrade, reported to the observer passed to
+/// `upgrade_canister_with_observer`.
+///
+/// Events can be persisted or exposed over Candid, e.g., to let operators inspect in-flight
+/// upgrades. They don't carry timestamps; the observer can add them using `ic_cdk::api::time()`.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum UpgradeEvent {
+    /// The upgrade has moved to the given stage.
+    StageStarted(UpgradeStage),
+    /// A call is about to be made at the given stage. Attempts are numbered from 1 within each
+    /// stage, and include retries of failed calls, as well as the `canister_info` calls made to
+    /// resolve installation attempts with unknown outcomes. Waiting, e.g., for a rate limit,
+    /// doesn't count as an attempt.
+    Attempt { stage: UpgradeStage, attempt: u32 },
+    /// The interface check failed with the given error, but the upgrade goes ahead, as the
+    /// check's `warn_only` is set.
+    InterfaceCheckFailed(String),
+    /// The target's version and module before the installation.
+    ObservedVersion {
+        total_num_changes: u64,
+        module_hash: Option<Vec<u8>>,
+    },
+    /// The outcome of checking whether an installation attempt with an unknown outcome went
+    /// through.
+    VersionCheck(VersionChangeCheck),
+    /// The upgrade succeeded.
+    Succeeded,
+    /// The target was already up to date, and the upgrade was skipped.
+    AlreadyUpToDate,
+    /// The upgrade failed at the given stage.
+    Failed(UpgradeStage),
+}
+
+/// Tracks the progress of an upgrade and reports it to an (optional) observer.
+pub(crate) struct Progress<'o> {
+    observer: Option<RefCell<&'o mut dyn FnMut(UpgradeEvent)>>,
+    stage: Cell<UpgradeStage>,
+    attempt: Cell<u32>,
+}
+
+impl<'o> Progress<'o> {
+    pub(crate) fn new(observer: &'o mut dyn FnMut(UpgradeEvent)) -> Self {
+        Self {
+            observer: Some(RefCell::new(observer)),
+            stage: Cell::new(UpgradeStage::UploadingChunks),
+            attempt: Cell::new(0),
+        }
+    }
+
+    /// A tracker that doesn't report anything.
+    pub(crate) fn silent() -> Self {
+        Self {
+            observer: None,
+            stage: Cell::new(UpgradeStage::UploadingChunks),
+            attempt: Cell::new(0),
+        }
+    }
+
+    pub(crate) fn emit(&self, event: UpgradeEvent) {
+        if let Some(observer) = &self.observer {
+            (observer.borrow_mut())(event);
+        }
+    }
+
+    /// Moves the upgrade to the given stage.
+    pub(crate) fn enter(&self, stage: UpgradeStage) {
+        self.stage.set(stage);
+        self.attempt.set(0);
+        self.emit(UpgradeEvent::StageStarted(stage));
+    }
+
+    /// Reports an attempt at the current stage.
+    pub(crate) fn attempt(&self) {
+        let attempt = self.attempt.get() + 1;
+        self.attempt.set(attempt);
+        self.emit(UpgradeEvent::Attempt {
+            stage: self.stage.get(),
+            attempt,
+        });
+    }
+}
+
+/// A call that runs a hook whenever it's made, i.e., right before each attempt of the retry
+/// functions, after any waiting for rate limits.
+#[derive(Clone)]
+pub(crate) struct ObservedCall<'h, C> {
+    call: C,
+    on_attempt: Option<&'h dyn Fn()>,
+}
+
+impl<'h, C> ObservedCall<'h, C> {
+    pub(crate) fn new(call: C, on_attempt: Option<&'h dyn Fn()>) -> Self {
+        Self { call, on_attempt }
+    }
+}
+
+impl<C: RetryableCall> IntoFuture for ObservedCall<'_, C> {
+    type Output = Result<Response, CallFailed>;
+    type IntoFuture = C::IntoFuture;
+
+    fn into_future(self) -> Self::IntoFuture {
+        if let Some(on_attempt) = self.on_attempt {
+            on_attempt();
+        }
+        self.call.into_future()
+    }
+}
+
+impl<C: RetryableCall> RetryableCall for ObservedCall<'_, C> {
+    fn callee(&self) -> Option<(Principal, &str)> {
+        self.call.callee()
+    }
+}
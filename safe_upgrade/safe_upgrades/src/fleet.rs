@@ -0,0 +1,314 @@
+use crate::{
+    upgrade_canister, CanisterId, ConcurrentChange, UpgradeError, UpgradeErrorReason,
+    UpgradeOptions, UpgradeOutcome, UpgradeStage, WasmModule,
+};
+use candid::CandidType;
+use futures::stream::{self, StreamExt};
+use ic_call_retry::RetryError;
+use serde::{Deserialize, Serialize};
+
+/// How to roll out an upgrade to a fleet of canisters.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RolloutConfig {
+    /// The number of canisters to upgrade first, one at a time. The rollout halts as soon as one
+    /// of them fails, before the next one is upgraded.
+    pub canary_size: usize,
+    /// The number of canisters in each of the following waves.
+    pub wave_size: usize,
+    /// The maximum number of upgrades in progress at the same time within a wave.
+    pub max_in_flight: usize,
+    /// The rollout halts after a wave in which more than this fraction of the upgrades didn't
+    /// succeed. For example, `0.0` halts on any failure, and `1.0` never halts.
+    pub max_failure_rate: f64,
+}
+
+/// The outcome of the upgrade of a single canister in a rollout.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum TargetOutcome {
+    Upgraded,
+    /// The canister was already running the new module (see the `skip_if_up_to_date` option).
+    AlreadyUpToDate,
+    /// The upgrade failed at the given stage. The reason is the `Debug` representation of the
+    /// `UpgradeErrorReason`, as the reason itself can't be persisted with the rollout.
+    Failed {
+        stage: UpgradeStage,
+        reason: String,
+    },
+    /// We don't know whether the upgrade went through; it stalled at the given stage, for the
+    /// given reason (as for `Failed`).
+    StatusUnknown {
+        stage: UpgradeStage,
+        reason: String,
+    },
+    /// Someone else changed the canister during the upgrade; see the report for details.
+    ConcurrentChangeDetected(ConcurrentChange),
+}
+
+impl From<&UpgradeError> for TargetOutcome {
+    fn from(error: &UpgradeError) -> Self {
+        let stage = error.stage;
+        let reason = format!("{:?}", error.reason);
+        match &error.reason {
+            UpgradeErrorReason::ConcurrentChangeDetected(report) => {
+                TargetOutcome::ConcurrentChangeDetected(report.clone())
+            }
+            UpgradeErrorReason::RetryError(RetryError::StatusUnknown(_))
+            | UpgradeErrorReason::StopFailed {
+                error: RetryError::StatusUnknown(_),
+                ..
+            } => TargetOutcome::StatusUnknown { stage, reason },
+            _ => TargetOutcome::Failed { stage, reason },
+        }
+    }
+}
+
+impl TargetOutcome {
+    fn succeeded(&self) -> bool {
+        matches!(
+            self,
+            TargetOutcome::Upgraded | TargetOutcome::AlreadyUpToDate
+        )
+    }
+}
+
+/// The outcome of the upgrade of a single canister, along with the wave it was upgraded in.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TargetResult {
+    pub target_id: CanisterId,
+    /// The wave the canister was upgraded in; the canary is wave 0.
+    pub wave: usize,
+    pub outcome: TargetOutcome,
+}
+
+/// Why a rollout halted.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum HaltReason {
+    /// An upgrade of a canary canister didn't succeed. The remaining canaries, if any, are
+    /// upgraded (one at a time, as canaries) when the rollout is resumed.
+    CanaryFailed,
+    /// Too many upgrades in the wave didn't succeed.
+    FailureRateExceeded {
+        wave: usize,
+        failed: usize,
+        total: usize,
+    },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum RolloutStatus {
+    /// There are canisters left to upgrade.
+    InProgress,
+    /// The rollout stopped because of failures. Call `resume` to continue.
+    Halted(HaltReason),
+    /// All canisters have been processed (though not necessarily upgraded successfully; see the
+    /// results).
+    Completed,
+}
+
+/// A rollout of an upgrade to a fleet of identical canisters.
+///
+/// The canisters are upgraded in waves using `upgrade_canister`: first the canary canisters, one
+/// at a time, and then waves of `wave_size` canisters, with up to `max_in_flight` upgrades in
+/// progress at the same time. After each wave, the rollout halts if the wave's failure rate is
+/// too high, leaving the remaining canisters untouched until the rollout is resumed.
+/// Like `upgrade_canister`, each upgrade holds its target's `UpgradeLock`, so a canister that is
+/// being upgraded by something else in this canister fails at the `Locking` stage (unless the
+/// `wait_for_lock` option is set).
+///
+/// The rollout can be persisted (e.g., in stable memory) using Candid or serde; `run` hands it to
+/// its `persist` function before and after every wave. If the rollout is interrupted during a
+/// wave (e.g., by an upgrade of the upgrading canister), the canisters of that wave remain
+/// `in_flight`, and their upgrades are run again when the rollout is run again. Set the
+/// `skip_if_up_to_date` option to avoid upgrading the ones that were already upgraded twice.
+/// Canisters whose upgrade didn't succeed aren't retried; their outcomes are recorded in the
+/// results. Note that `StatusUnknown` outcomes count as failures.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+pub struct Rollout {
+    pub wasm_module: WasmModule,
+    pub arg: Vec<u8>,
+    pub options: UpgradeOptions,
+    pub config: RolloutConfig,
+    /// The canisters that haven't been upgraded yet, in the order of the rollout.
+    pub pending: Vec<CanisterId>,
+    /// The canisters of the wave in progress, whose upgrades may have started.
+    pub in_flight: Vec<CanisterId>,
+    /// The outcomes of the upgrades so far, in the order of the rollout.
+    pub results: Vec<TargetResult>,
+    /// The next wave to run; the canary is wave 0.
+    pub next_wave: usize,
+    pub status: RolloutStatus,
+}
+
+impl Rollout {
+    /// Creates a rollout of the module to the given canisters. The first `canary_size`
+    /// canisters are the canaries. No calls are made until the rollout is run.
+    pub fn new(
+        targets: Vec<CanisterId>,
+        wasm_module: WasmModule,
+        arg: Vec<u8>,
+        options: UpgradeOptions,
+        config: RolloutConfig,
+    ) -> Self {
+        let status = if targets.is_empty() {
+            RolloutStatus::Completed
+        } else {
+            RolloutStatus::InProgress
+        };
+        Self {
+            wasm_module,
+            arg,
+            options,
+            config,
+            pending: targets,
+            in_flight: vec![],
+            results: vec![],
+            next_wave: 0,
+            status,
+        }
+    }
+
+    /// Continues a halted rollout where it stopped. Has no effect on rollouts that aren't halted.
+    pub fn resume(&mut self) {
+        if let RolloutStatus::Halted(_) = self.status {
+            self.status = RolloutStatus::InProgress;
+        }
+    }
+
+    /// Runs the waves of the rollout until it completes or halts.
+    ///
+    /// # Arguments
+    /// * `new_stop_trying` - Creates the function that determines when to stop retrying the
+    ///   calls of a single upgrade. It's invoked once per canister; for example,
+    ///   `|| when_out_of_time_or_stopping(&deadline)`.
+    /// * `persist` - Called with the rollout before and after every wave, e.g., to save it to
+    ///   stable memory
+    ///
+    /// # Returns
+    /// The status of the rollout once it has completed or halted.
+    pub async fn run<F, P, S>(&mut self, mut new_stop_trying: F, mut persist: S) -> &RolloutStatus
+    where
+        F: FnMut() -> P,
+        P: FnMut() -> bool,
+        S: FnMut(&Rollout),
+    {
+        while self.status == RolloutStatus::InProgress {
+            if self.in_flight.is_empty() {
+                self.start_wave();
+                persist(self);
+            }
+            self.run_wave(&mut new_stop_trying).await;
+            persist(self);
+        }
+        &self.status
+    }
+
+    /// Moves the canisters of the next wave from `pending` to `in_flight`.
+    fn start_wave(&mut self) {
+        let size = if self.next_wave == 0 {
+            // The canary wave may have been cut short by a failure before the rollout was resumed
+            let canaries_done = self
+                .results
+                .iter()
+                .filter(|result| result.wave == 0)
+                .count();
+            self.config.canary_size.saturating_sub(canaries_done)
+        } else {
+            self.config.wave_size.max(1)
+        };
+        self.in_flight = self.pending.drain(..size.min(self.pending.len())).collect();
+    }
+
+    /// Runs the upgrades of the `in_flight` canisters, and updates the status accordingly.
+    async fn run_wave<F, P>(&mut self, new_stop_trying: &mut F)
+    where
+        F: FnMut() -> P,
+        P: FnMut() -> bool,
+    {
+        let wave = self.next_wave;
+        let targets: Vec<_> = self
+            .in_flight
+            .iter()
+            .map(|target_id| (*target_id, new_stop_trying()))
+            .collect();
+
+        let wasm_module = &self.wasm_module;
+        let arg = &self.arg;
+        let options = &self.options;
+        let upgrade = |(target_id, mut stop_trying): (CanisterId, P)| async move {
+            let result = upgrade_canister(
+                target_id,
+                wasm_module.clone(),
+                arg.clone(),
+                options.clone(),
+                &mut stop_trying,
+            )
+            .await;
+            TargetResult {
+                target_id,
+                wave,
+                outcome: match result {
+                    Ok(UpgradeOutcome::Upgraded) => TargetOutcome::Upgraded,
+                    Ok(UpgradeOutcome::AlreadyUpToDate) => TargetOutcome::AlreadyUpToDate,
+                    Err(error) => TargetOutcome::from(&error),
+                },
+            }
+        };
+
+        if wave == 0 {
+            let mut upgraded = 0;
+            let mut canary_failed = false;
+            for target in targets {
+                let result = upgrade(target).await;
+                upgraded += 1;
+                canary_failed = !result.outcome.succeeded();
+                self.results.push(result);
+                if canary_failed {
+                    break;
+                }
+            }
+            // The remaining canaries go back to the front of the line
+            let remaining: Vec<_> = self.in_flight.drain(..).skip(upgraded).collect();
+            self.pending.splice(0..0, remaining);
+            self.status = if canary_failed {
+                RolloutStatus::Halted(HaltReason::CanaryFailed)
+            } else {
+                self.next_wave += 1;
+                self.status_after_wave()
+            };
+            return;
+        }
+
+        let wave_results: Vec<TargetResult> = stream::iter(targets)
+            .map(upgrade)
+            .buffered(self.config.max_in_flight.max(1))
+            .collect()
+            .await;
+        self.in_flight.clear();
+
+        let total = wave_results.len();
+        let failed = wave_results
+            .iter()
+            .filter(|result| !result.outcome.succeeded())
+            .count();
+        self.results.extend(wave_results);
+        self.next_wave += 1;
+
+        self.status = if total > 0 && failed as f64 / total as f64 > self.config.max_failure_rate {
+            RolloutStatus::Halted(HaltReason::FailureRateExceeded {
+                wave,
+                failed,
+                total,
+            })
+        } else {
+            self.status_after_wave()
+        };
+    }
+
+    fn status_after_wave(&self) -> RolloutStatus {
+        if self.pending.is_empty() {
+            RolloutStatus::Completed
+        } else {
+            RolloutStatus::InProgress
+        }
+    }
+}
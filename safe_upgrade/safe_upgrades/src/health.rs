@@ -0,0 +1,76 @@
+use crate::proxy::Target;
+use crate::{add_stage, UpgradeError, UpgradeErrorReason, UpgradeStage};
+use candid::utils::{encode_args, ArgumentEncoder};
+use candid::{CandidType, IDLArgs};
+use ic_call_retry::{call_idempotent_method_with_retry, Call};
+use serde::{Deserialize, Serialize};
+
+/// A post-upgrade check of the target, which calls a method on the upgraded and restarted target
+/// and compares the response with an expected one.
+///
+/// The method can be either a query or an update method; it's called with a bounded-wait call,
+/// which is retried on failure, so it should be idempotent. The responses are compared as Candid
+/// values, so the expected response doesn't need to be encoded byte-for-byte the same as the
+/// actual one.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HealthCheck {
+    /// The method to call on the target.
+    pub method: String,
+    /// The Candid-encoded argument of the call.
+    pub arg: Vec<u8>,
+    /// The Candid-encoded response that the method must return for the target to be healthy.
+    pub expected_response: Vec<u8>,
+}
+
+impl HealthCheck {
+    /// Creates a check that calls `method` without arguments, and expects it to return `expected`.
+    pub fn new<T: CandidType>(method: impl Into<String>, expected: &T) -> Self {
+        Self {
+            method: method.into(),
+            arg: encode_args(()).expect("Failed to encode the empty argument"),
+            expected_response: encode_args((expected,))
+                .expect("Failed to encode the expected response"),
+        }
+    }
+
+    /// Sets the arguments of the call.
+    pub fn with_args<A: ArgumentEncoder>(mut self, args: A) -> Self {
+        self.arg = encode_args(args).expect("Failed to encode the health check arguments");
+        self
+    }
+}
+
+/// Runs the health check against the target, with bounded-wait calls retried until
+/// `stop_trying` returns true.
+pub(crate) async fn verify<P>(
+    target: Target<'_>,
+    check: &HealthCheck,
+    stop_trying: &mut P,
+) -> Result<(), UpgradeError>
+where
+    P: FnMut() -> bool,
+{
+    let response = call_idempotent_method_with_retry(
+        target.observe(Call::bounded_wait(target.id, &check.method).with_raw_args(&check.arg)),
+        stop_trying,
+    )
+    .await
+    .map_err(add_stage(UpgradeStage::Verifying))?;
+
+    let failed = |message: String| UpgradeError {
+        stage: UpgradeStage::Verifying,
+        reason: UpgradeErrorReason::HealthCheckFailed(message),
+    };
+    let actual = IDLArgs::from_bytes(&response)
+        .map_err(|e| failed(format!("Couldn't decode the response: {}", e)))?;
+    let expected = IDLArgs::from_bytes(&check.expected_response)
+        .map_err(|e| failed(format!("Couldn't decode the expected response: {}", e)))?;
+    if actual.args == expected.args {
+        Ok(())
+    } else {
+        Err(failed(format!(
+            "Method {} returned {}, expected {}",
+            check.method, actual, expected
+        )))
+    }
+}
@@ -0,0 +1,143 @@
+use crate::proxy::Target;
+use candid::{CandidType, Principal};
+use ic_call_retry::{call_idempotent_method_with_retry, RetryError};
+use ic_cdk::management_canister::{CanisterStatusArgs, CanisterStatusResult};
+use ic_management_canister_types::{
+    CanisterStatusType, Change, ChangeDetails, ChangeOrigin, CodeDeploymentMode, SnapshotId,
+};
+use serde::{Deserialize, Serialize};
+
+/// The maximum number of changes that `canister_info` returns; the canister history doesn't keep
+/// more than this many recent changes.
+pub(crate) const MAX_RECENT_CHANGES: u64 = 20;
+
+/// What a change recorded in the target's canister history did.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// The deployment that we're performing: made by this canister, with the expected mode and
+    /// module.
+    OwnDeployment,
+    /// A deployment made by another principal, or by this canister but with a different mode or
+    /// module.
+    OtherDeployment {
+        mode: CodeDeploymentMode,
+        module_hash: Vec<u8>,
+    },
+    ControllersChange {
+        controllers: Vec<Principal>,
+    },
+    CodeUninstall,
+    LoadSnapshot {
+        snapshot_id: SnapshotId,
+    },
+    Creation,
+}
+
+/// A change recorded in the target's canister history, classified relative to our deployment.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ObservedChange {
+    pub canister_version: u64,
+    pub timestamp_nanos: u64,
+    /// Who made the change.
+    pub origin: ChangeOrigin,
+    pub kind: ChangeKind,
+}
+
+/// A report of the changes made to the target since we recorded its version before installing
+/// the new module, describing why the upgrade is considered to have raced with someone else.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ConcurrentChange {
+    /// The target's `total_num_changes` before the installation.
+    pub baseline_num_changes: u64,
+    /// The target's `total_num_changes` when the report was made.
+    pub current_num_changes: u64,
+    /// The changes since the baseline, oldest first, including our own deployment (if it
+    /// happened).
+    pub changes: Vec<ObservedChange>,
+    /// The number of changes since the baseline that are no longer in the canister history, which
+    /// only keeps the most recent changes. Nothing is known about these changes.
+    pub unobserved_changes: u64,
+    /// The target's status. Anything other than `Stopped` means that someone started the target
+    /// while it was being upgraded; the canister history doesn't record stops and starts.
+    pub status: CanisterStatusType,
+}
+
+impl ConcurrentChange {
+    /// The changes made by someone else. Note that more than one `OwnDeployment` also indicates
+    /// a concurrent change, e.g., a parallel upgrade by this canister.
+    pub fn offending_changes(&self) -> impl Iterator<Item = &ObservedChange> {
+        self.changes
+            .iter()
+            .filter(|change| change.kind != ChangeKind::OwnDeployment)
+    }
+
+    fn own_deployments(&self) -> usize {
+        self.changes.len() - self.offending_changes().count()
+    }
+
+    /// Whether the changes show that nobody but us touched the target. Returns the number of our
+    /// own deployments in that case.
+    pub(crate) fn undisturbed_deployments(&self) -> Option<usize> {
+        let undisturbed = self.offending_changes().next().is_none()
+            && self.unobserved_changes == 0
+            && self.status == CanisterStatusType::Stopped
+            && self.own_deployments() <= 1;
+        undisturbed.then(|| self.own_deployments())
+    }
+}
+
+/// Classifies a change from the canister history relative to a deployment of the module with the
+/// given hash and mode by us, as determined by `Target::is_own_origin`.
+pub(crate) fn classify(
+    change: Change,
+    mode: CodeDeploymentMode,
+    module_hash: &[u8],
+    target: &Target<'_>,
+) -> ObservedChange {
+    let by_us = target.is_own_origin(&change.origin);
+    let kind = match change.details {
+        ChangeDetails::CodeDeployment(deployment)
+            if by_us && deployment.mode == mode && deployment.module_hash == module_hash =>
+        {
+            ChangeKind::OwnDeployment
+        }
+        ChangeDetails::CodeDeployment(deployment) => ChangeKind::OtherDeployment {
+            mode: deployment.mode,
+            module_hash: deployment.module_hash,
+        },
+        ChangeDetails::ControllersChange(record) => ChangeKind::ControllersChange {
+            controllers: record.controllers,
+        },
+        ChangeDetails::CodeUninstall => ChangeKind::CodeUninstall,
+        ChangeDetails::LoadSnapshot(record) => ChangeKind::LoadSnapshot {
+            snapshot_id: record.snapshot_id,
+        },
+        ChangeDetails::Creation(_) => ChangeKind::Creation,
+    };
+    ObservedChange {
+        canister_version: change.canister_version,
+        timestamp_nanos: change.timestamp_nanos,
+        origin: change.origin,
+        kind,
+    }
+}
+
+pub(crate) async fn bounded_wait_status<P>(
+    target: Target<'_>,
+    stop_trying: &mut P,
+) -> Result<CanisterStatusType, RetryError>
+where
+    P: FnMut() -> bool,
+{
+    let args = CanisterStatusArgs {
+        canister_id: target.id,
+    };
+    let result: CanisterStatusResult = call_idempotent_method_with_retry(
+        target.management_call("canister_status", &args),
+        stop_trying,
+    )
+    .await?
+    .candid()
+    .unwrap();
+    Ok(result.status)
+}
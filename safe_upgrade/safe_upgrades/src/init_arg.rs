@@ -0,0 +1,95 @@
+use crate::{wasm, WasmModule};
+use candid::utils::ArgumentEncoder;
+use candid::{CandidType, IDLArgs};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Why an install argument failed validation against the new module.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ArgValidationError {
+    /// The argument isn't well-formed Candid.
+    MalformedArg(String),
+    /// The module is a `WasmModule::ChunkedModule`, whose bytes aren't available for inspection.
+    ModuleNotAvailable,
+    /// The module couldn't be parsed.
+    InvalidModule(String),
+    /// The module has no `candid:service` metadata section to validate the argument against.
+    MissingInterface,
+    /// The module's `candid:service` metadata couldn't be parsed.
+    InvalidInterface(String),
+    /// The argument doesn't match the init argument types of the module's interface.
+    TypeMismatch(String),
+}
+
+/// Checks an install argument against the new module.
+///
+/// The argument must decode as the init arguments of the service declared in the module's
+/// `candid:service` metadata section (public or private), which are also the arguments of the
+/// `post_upgrade` hook. An empty argument stands for no arguments, and is thus valid if all init
+/// arguments are optional. Gzip-compressed modules are decompressed first.
+///
+/// Parsing the interface requires the `candid_validation` feature, which is enabled by default.
+/// Without it, the argument is only checked to be well-formed Candid.
+///
+/// # Returns
+/// * `Ok(())` if the argument is valid for the module
+/// * `Err(ArgValidationError)` otherwise.
+pub fn validate_arg(wasm_module: &WasmModule, arg: &[u8]) -> Result<(), ArgValidationError> {
+    let no_args;
+    let arg = if arg.is_empty() {
+        no_args = candid::encode_args(()).expect("Candid encoding failed");
+        &no_args
+    } else {
+        arg
+    };
+    IDLArgs::from_bytes(arg).map_err(|e| ArgValidationError::MalformedArg(e.to_string()))?;
+    let bytes = match wasm_module {
+        WasmModule::Bytes(bytes) | WasmModule::Auto { bytes, .. } => bytes,
+        WasmModule::ChunkedModule(_) => return Err(ArgValidationError::ModuleNotAvailable),
+    };
+    check_against_interface(bytes, arg)
+}
+
+#[cfg(feature = "candid_validation")]
+fn check_against_interface(module: &[u8], arg: &[u8]) -> Result<(), ArgValidationError> {
+    use candid_parser::utils::{instantiate_candid, CandidSource};
+
+    let service = wasm::candid_service(module)
+        .map_err(ArgValidationError::InvalidModule)?
+        .ok_or(ArgValidationError::MissingInterface)?;
+    let (init_types, (env, _)) = instantiate_candid(CandidSource::Text(&service))
+        .map_err(|e| ArgValidationError::InvalidInterface(e.to_string()))?;
+    IDLArgs::from_bytes_with_types(arg, &env, &init_types)
+        .map_err(|e| ArgValidationError::TypeMismatch(e.to_string()))?;
+    Ok(())
+}
+
+#[cfg(not(feature = "candid_validation"))]
+fn check_against_interface(module: &[u8], _arg: &[u8]) -> Result<(), ArgValidationError> {
+    // Still read the interface, so that broken modules are rejected regardless of the feature.
+    wasm::candid_service(module).map_err(ArgValidationError::InvalidModule)?;
+    Ok(())
+}
+
+/// Encodes a typed install argument with Candid, and validates it against the new module using
+/// `validate_arg`, e.g.:
+///
+/// ```ignore
+/// let arg = encode_validated_arg(&wasm_module, (InitArgs { ledger_id },))?;
+/// upgrade_canister(target_id, wasm_module, arg, options, &mut stop_trying).await
+/// ```
+pub fn encode_validated_arg<A: ArgumentEncoder>(
+    wasm_module: &WasmModule,
+    args: A,
+) -> Result<Vec<u8>, ArgValidationError> {
+    let arg =
+        candid::encode_args(args).map_err(|e| ArgValidationError::MalformedArg(e.to_string()))?;
+    validate_arg(wasm_module, &arg)?;
+    Ok(arg)
+}
+
+/// The SHA-256 hash of an install argument. Record it when deploying a module, to later skip
+/// redundant upgrades with `SkipIfUpToDate::ModuleAndArgMatch`.
+pub fn arg_hash(arg: &[u8]) -> Vec<u8> {
+    Sha256::digest(arg).to_vec()
+}
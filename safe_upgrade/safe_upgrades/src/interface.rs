@@ -0,0 +1,139 @@
+use crate::proxy::Target;
+use crate::{wasm, CanisterId, WasmModule};
+use candid::CandidType;
+use ic_call_retry::{call_idempotent_method_with_retry, Call, RetryError};
+use serde::{Deserialize, Serialize};
+
+/// Where to find the Candid interface of the module that the target currently runs.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum InterfaceSource {
+    /// The installed module, read from its `candid:service` metadata section. The module may be
+    /// gzip-compressed.
+    Module(#[serde(with = "serde_bytes")] Vec<u8>),
+    /// The interface itself, as Candid service text.
+    Service(String),
+    /// Queried from the target by calling the given method without arguments, which must reply
+    /// with the interface as text, e.g., `__get_candid_interface_tmp_hack` as exported by
+    /// `ic_cdk::export_candid!()`. Canisters can't read each other's metadata directly.
+    Target { method: String },
+}
+
+/// Checks that the new module's Candid interface is compatible with the old one, when set as the
+/// `interface_check` upgrade option. See `check_interface_compatibility`.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceCheck {
+    pub old_interface: InterfaceSource,
+    /// Only report a failed check with an `UpgradeEvent::InterfaceCheckFailed` event to the
+    /// observer, and upgrade anyway. By default, a failed check stops the upgrade. Without the
+    /// `candid_validation` feature, the upgrade is stopped regardless, as nothing is checked.
+    pub warn_only: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum InterfaceCheckError {
+    /// The new module is a `WasmModule::ChunkedModule`, whose bytes aren't available for
+    /// inspection.
+    ModuleNotAvailable,
+    /// The new or the old module couldn't be parsed.
+    InvalidModule(String),
+    /// The new or the old module has no `candid:service` metadata section.
+    MissingInterface,
+    /// Querying the old interface from the target failed.
+    QueryFailed(RetryError),
+    /// The old or the new interface couldn't be parsed.
+    InvalidInterface(String),
+    /// The new interface isn't a subtype of the old one, so existing clients may break.
+    Incompatible(String),
+    /// Checking the interfaces requires the `candid_validation` feature, which is disabled.
+    CheckUnavailable,
+}
+
+/// Checks that the Candid interface of the new module, from its `candid:service` metadata section
+/// (public or private), is a subtype of the old interface, i.e., that clients of the old interface
+/// keep working after the upgrade.
+///
+/// This performs the subtype check of `candid_parser`, and thus requires the `candid_validation`
+/// feature, which is enabled by default. Without it, the check always fails with
+/// `InterfaceCheckError::CheckUnavailable`.
+///
+/// # Arguments
+/// * `target_id` - The canister to query for `InterfaceSource::Target`
+/// * `wasm_module` - The new module
+/// * `old_interface` - Where to find the old interface
+/// * `stop_trying` - A function that determines when to stop (re)trying the query of the target
+///
+/// # Returns
+/// * `Ok(())` if the new interface is compatible with the old one
+/// * `Err(InterfaceCheckError)` otherwise.
+pub async fn check_interface_compatibility<P>(
+    target_id: CanisterId,
+    wasm_module: &WasmModule,
+    old_interface: &InterfaceSource,
+    stop_trying: &mut P,
+) -> Result<(), InterfaceCheckError>
+where
+    P: FnMut() -> bool,
+{
+    check_interface(
+        Target::direct(target_id),
+        wasm_module,
+        old_interface,
+        stop_trying,
+    )
+    .await
+}
+
+pub(crate) async fn check_interface<P>(
+    target: Target<'_>,
+    wasm_module: &WasmModule,
+    old_interface: &InterfaceSource,
+    stop_trying: &mut P,
+) -> Result<(), InterfaceCheckError>
+where
+    P: FnMut() -> bool,
+{
+    if cfg!(not(feature = "candid_validation")) {
+        return Err(InterfaceCheckError::CheckUnavailable);
+    }
+    let new = match wasm_module {
+        WasmModule::Bytes(bytes) | WasmModule::Auto { bytes, .. } => service_of(bytes)?,
+        WasmModule::ChunkedModule(_) => return Err(InterfaceCheckError::ModuleNotAvailable),
+    };
+    let old = match old_interface {
+        InterfaceSource::Module(bytes) => service_of(bytes)?,
+        InterfaceSource::Service(service) => service.clone(),
+        InterfaceSource::Target { method } => {
+            let call = target.observe(Call::bounded_wait(target.id, method));
+            call_idempotent_method_with_retry(call, stop_trying)
+                .await
+                .map_err(InterfaceCheckError::QueryFailed)?
+                .candid()
+                .map_err(|e| InterfaceCheckError::InvalidInterface(e.to_string()))?
+        }
+    };
+    compatible(&new, &old)
+}
+
+fn service_of(module: &[u8]) -> Result<String, InterfaceCheckError> {
+    wasm::candid_service(module)
+        .map_err(InterfaceCheckError::InvalidModule)?
+        .ok_or(InterfaceCheckError::MissingInterface)
+}
+
+#[cfg(feature = "candid_validation")]
+fn compatible(new: &str, old: &str) -> Result<(), InterfaceCheckError> {
+    use candid_parser::utils::{service_compatible, CandidSource};
+
+    for service in [new, old] {
+        CandidSource::Text(service)
+            .load()
+            .map_err(|e| InterfaceCheckError::InvalidInterface(e.to_string()))?;
+    }
+    service_compatible(CandidSource::Text(new), CandidSource::Text(old))
+        .map_err(|e| InterfaceCheckError::Incompatible(e.to_string()))
+}
+
+#[cfg(not(feature = "candid_validation"))]
+fn compatible(_new: &str, _old: &str) -> Result<(), InterfaceCheckError> {
+    Err(InterfaceCheckError::CheckUnavailable)
+}
@@ -0,0 +1,241 @@
+use crate::events::Progress;
+use crate::lock::lock_target;
+use crate::proxy::Target;
+use crate::{
+    add_stage, bounded_wait_canister_info, bounded_wait_start, deployment_mode, health,
+    install_from_baseline, is_up_to_date, pre_flight, prepare_module, stop_or_recover,
+    version_change_check, CanisterId, UpgradeError, UpgradeErrorReason, UpgradeOptions,
+    UpgradeOutcome, UpgradeStage, VersionChangeCheck, WasmModule,
+};
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+/// The state of an `UpgradeJob`.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum UpgradeJobState {
+    /// The new module and argument are being checked as requested by the options, before the
+    /// target is touched. If the `skip_if_up_to_date` option is set, the target is checked too.
+    PreFlight,
+    /// The target is being stopped (and large modules uploaded into a chunk store beforehand).
+    Stopping,
+    /// The target is stopped, but we don't know its number of changes before the upgrade yet.
+    Stopped,
+    /// The module is being installed. The installation may or may not have happened already;
+    /// resuming the job determines which, by comparing the target's `total_num_changes` to the
+    /// baseline recorded before the first installation attempt.
+    Installing { baseline_num_changes: u64 },
+    /// The module has been installed, but the target hasn't been started yet.
+    Installed,
+    /// The target is being started (and checked, if a health check is configured).
+    Starting,
+    /// The upgrade is complete.
+    Done,
+    /// The target was already running the new module, so the upgrade was skipped (see the
+    /// `skip_if_up_to_date` option).
+    AlreadyUpToDate,
+}
+
+/// A resumable upgrade of a single canister.
+///
+/// `upgrade_canister` keeps track of its progress only in its async call stack. If it gives up
+/// (e.g., because of a deadline), the target may be left stopped, and the caller doesn't know how
+/// far the upgrade got. An `UpgradeJob` instead records its progress explicitly, so that it can be
+/// resumed at any point: later in the same message, from a timer, or after the upgrading canister
+/// has itself been upgraded.
+///
+/// The job can be stored anywhere, e.g., serialized into stable memory with Candid or serde. To
+/// survive an upgrade of the upgrading canister, persist the job after every state transition
+/// (see `run`). Resuming a job is safe in every state, as each step is either idempotent or
+/// resolves the outcome of previous attempts first.
+///
+/// While `run` or `step` is in progress, the job holds the target's `UpgradeLock`, so other
+/// upgrades of the target by this canister fail or wait (see the `wait_for_lock` option). The
+/// lock isn't held between runs, though, so don't start other upgrades of the target while a job
+/// is unfinished.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+pub struct UpgradeJob {
+    pub target_id: CanisterId,
+    pub wasm_module: WasmModule,
+    pub arg: Vec<u8>,
+    pub options: UpgradeOptions,
+    pub state: UpgradeJobState,
+}
+
+impl UpgradeJob {
+    /// Creates a job that upgrades the target to the given module. No calls are made until the
+    /// job is run.
+    pub fn new(
+        target_id: CanisterId,
+        wasm_module: WasmModule,
+        arg: Vec<u8>,
+        options: UpgradeOptions,
+    ) -> Self {
+        Self {
+            target_id,
+            wasm_module,
+            arg,
+            options,
+            state: UpgradeJobState::PreFlight,
+        }
+    }
+
+    /// Whether the upgrade is complete (or was skipped).
+    pub fn is_done(&self) -> bool {
+        matches!(
+            self.state,
+            UpgradeJobState::Done | UpgradeJobState::AlreadyUpToDate
+        )
+    }
+
+    /// Runs the job until it's done, or until a step fails.
+    ///
+    /// Calls `persist` with the job after every state transition, so that the job can be saved,
+    /// e.g., to stable memory. On failure, the job stays in the state in which the error occurred,
+    /// and can be resumed by calling `run` again. Note that some errors (such as
+    /// `UpgradeErrorReason::ConcurrentChangeDetected`) will keep occurring on every resumption.
+    ///
+    /// # Arguments
+    /// * `stop_trying` - A function that determines when to stop retrying the calls
+    /// * `persist` - Called with the job after every state transition
+    ///
+    /// # Returns
+    /// * `Ok(UpgradeOutcome)` if the upgrade is complete or was skipped.
+    /// * `Err(UpgradeError)` if a step failed or its status is unknown.
+    pub async fn run<P, S>(
+        &mut self,
+        stop_trying: &mut P,
+        mut persist: S,
+    ) -> Result<UpgradeOutcome, UpgradeError>
+    where
+        P: FnMut() -> bool,
+        S: FnMut(&UpgradeJob),
+    {
+        let _lock = lock_target(self.target_id, self.options.wait_for_lock, stop_trying).await?;
+        while !self.is_done() {
+            self.step_locked(stop_trying).await?;
+            persist(self);
+        }
+        Ok(match self.state {
+            UpgradeJobState::AlreadyUpToDate => UpgradeOutcome::AlreadyUpToDate,
+            _ => UpgradeOutcome::Upgraded,
+        })
+    }
+
+    /// Performs the next step of the job, moving it into the next state.
+    ///
+    /// On failure, the job stays in its current state.
+    pub async fn step<P>(&mut self, stop_trying: &mut P) -> Result<(), UpgradeError>
+    where
+        P: FnMut() -> bool,
+    {
+        let _lock = lock_target(self.target_id, self.options.wait_for_lock, stop_trying).await?;
+        self.step_locked(stop_trying).await
+    }
+
+    async fn step_locked<P>(&mut self, stop_trying: &mut P) -> Result<(), UpgradeError>
+    where
+        P: FnMut() -> bool,
+    {
+        let target_id = self.target_id;
+        let target = Target::new(target_id, &self.options);
+        let next = match &self.state {
+            // The checks get their own state, as a resumed `Stopping` step only has the prepared
+            // module, whose bytes may no longer be available.
+            UpgradeJobState::PreFlight => {
+                pre_flight(
+                    target,
+                    &self.wasm_module,
+                    &self.arg,
+                    &self.options,
+                    &Progress::silent(),
+                    stop_trying,
+                )
+                .await?;
+                if let Some(check) = &self.options.skip_if_up_to_date {
+                    if is_up_to_date(target, check, &self.wasm_module, &self.arg, stop_trying)
+                        .await?
+                    {
+                        self.state = UpgradeJobState::AlreadyUpToDate;
+                        return Ok(());
+                    }
+                }
+                UpgradeJobState::Stopping
+            }
+            UpgradeJobState::Stopping => {
+                // Uploading is idempotent, and the prepared module is kept, so a resumed job
+                // doesn't upload again.
+                self.wasm_module =
+                    prepare_module(target, self.wasm_module.clone(), &self.arg, stop_trying)
+                        .await
+                        .map_err(|error| UpgradeError {
+                            stage: UpgradeStage::UploadingChunks,
+                            reason: UpgradeErrorReason::ChunkUploadFailed(error),
+                        })?;
+                stop_or_recover(target, self.options.restart_on_stop_failure, stop_trying).await?;
+                UpgradeJobState::Stopped
+            }
+            UpgradeJobState::Stopped => {
+                let info = bounded_wait_canister_info(target, None, stop_trying)
+                    .await
+                    .map_err(add_stage(UpgradeStage::ObtainingInfo))?;
+                if let Some(precondition) = &self.options.precondition {
+                    precondition.check(&info)?;
+                }
+                UpgradeJobState::Installing {
+                    baseline_num_changes: info.total_num_changes,
+                }
+            }
+            UpgradeJobState::Installing {
+                baseline_num_changes,
+            } => {
+                let mode = self.options.install_mode();
+                // A previous attempt may have gone through before the job was interrupted.
+                let check = version_change_check(
+                    target,
+                    deployment_mode(mode),
+                    &self.wasm_module,
+                    *baseline_num_changes,
+                    stop_trying,
+                )
+                .await
+                .map_err(add_stage(UpgradeStage::Installing))?;
+                match check {
+                    VersionChangeCheck::UpgradeSucceeded => (),
+                    VersionChangeCheck::NoChange => {
+                        install_from_baseline(
+                            target,
+                            mode,
+                            &self.wasm_module,
+                            &self.arg,
+                            *baseline_num_changes,
+                            &Progress::silent(),
+                            stop_trying,
+                        )
+                        .await?
+                    }
+                    VersionChangeCheck::ConcurrentChangeDetected(report) => {
+                        return Err(UpgradeError {
+                            stage: UpgradeStage::Installing,
+                            reason: UpgradeErrorReason::ConcurrentChangeDetected(report),
+                        })
+                    }
+                }
+                UpgradeJobState::Installed
+            }
+            UpgradeJobState::Installed => UpgradeJobState::Starting,
+            UpgradeJobState::Starting => {
+                bounded_wait_start(target, stop_trying)
+                    .await
+                    .map_err(add_stage(UpgradeStage::Starting))?;
+                if let Some(check) = &self.options.health_check {
+                    health::verify(target, check, stop_trying).await?;
+                }
+                UpgradeJobState::Done
+            }
+            UpgradeJobState::Done => UpgradeJobState::Done,
+            UpgradeJobState::AlreadyUpToDate => UpgradeJobState::AlreadyUpToDate,
+        };
+        self.state = next;
+        Ok(())
+    }
+}
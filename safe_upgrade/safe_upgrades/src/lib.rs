@@ -1,53 +1,274 @@
-use candid::Principal;
+use candid::{CandidType, Principal};
+use events::Progress;
 use ic_call_retry::{
     call_idempotent_method_with_retry, call_nonidempotent_method_with_retry,
-    when_out_of_time_or_stopping, Deadline, ErrorCause, RetryError,
+    when_max_retries_reached, ErrorCause, RetryError,
 };
-use ic_cdk::api::canister_self;
 use ic_cdk::call::CallErrorExt;
 use ic_cdk::management_canister::InstallChunkedCodeArgs;
 use ic_cdk::management_canister::{
-    CanisterInfoArgs, CanisterInfoResult, CanisterInstallMode, ChunkHash, ClearChunkStoreArgs,
-    InstallCodeArgs, UploadChunkArgs,
+    CanisterInfoArgs, CanisterInfoResult, CanisterInstallMode, ChunkHash, InstallCodeArgs,
+    UpgradeFlags, WasmMemoryPersistence,
 };
 use ic_management_canister_types::{
-    ChangeDetails, ChangeOrigin, StartCanisterArgs, StopCanisterArgs,
+    CanisterStatusType, CodeDeploymentMode, StartCanisterArgs, StopCanisterArgs,
 };
+use interface::check_interface;
+use lock::lock_target;
+use proxy::Target;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
-#[cfg(feature = "use_call_chaos")]
-use ic_call_chaos::Call;
-#[cfg(not(feature = "use_call_chaos"))]
-use ic_cdk::call::Call;
+mod chunks;
+mod controllers;
+mod events;
+mod fleet;
+mod health;
+mod history;
+mod init_arg;
+mod interface;
+mod job;
+mod lifecycle;
+mod lock;
+mod proxy;
+mod rollback;
+mod wasm;
+
+pub use chunks::{clear_chunk_store, upload_chunks, ChunkUploadError, MAX_CHUNK_SIZE};
+pub use controllers::{change_controllers, ControllerChange, ControllerChangeError};
+pub use events::UpgradeEvent;
+pub use fleet::{HaltReason, Rollout, RolloutConfig, RolloutStatus, TargetOutcome, TargetResult};
+pub use health::HealthCheck;
+pub use history::{ChangeKind, ConcurrentChange, ObservedChange};
+pub use init_arg::{arg_hash, encode_validated_arg, validate_arg, ArgValidationError};
+pub use interface::{
+    check_interface_compatibility, InterfaceCheck, InterfaceCheckError, InterfaceSource,
+};
+pub use job::{UpgradeJob, UpgradeJobState};
+pub use lifecycle::{
+    create_canister, delete_canister, deposit_cycles, uninstall_code, update_settings,
+    LifecycleError, LifecycleOperation,
+};
+pub use lock::{in_flight_upgrades, InFlightUpgrade, UpgradeLock};
+pub use proxy::{ManagementProxy, RelayArgs};
+pub use rollback::{
+    upgrade_canister_with_rollback, RollbackOutcome, RollbackStage, RollbackUpgradeError,
+    RollbackUpgradeSuccess,
+};
+pub use wasm::{
+    check_module, inspect_module, ModuleCheckError, ModuleChecks, ModuleInfo,
+    MAX_DECOMPRESSED_MODULE_SIZE,
+};
 
 /// Represents a canister's principal ID on the IC.
 pub type CanisterId = Principal;
 
 /// Describes the stage of the upgrade during which an error occurred
 /// or after which we could not confirm status.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum UpgradeStage {
+    /// Taking the target's `UpgradeLock`, before anything is done to the target.
+    Locking,
+    /// Checking the new module and argument, before anything is done to the target.
+    PreFlight,
+    /// Uploading a `WasmModule::Auto` module into a chunk store, before the target is stopped.
+    UploadingChunks,
     Stopping,
+    /// Taking a snapshot of the stopped target, in `upgrade_canister_with_rollback`.
+    TakingSnapshot,
     ObtainingInfo,
     Installing,
     Starting,
+    /// Checking the health of the upgraded and restarted target, using the `health_check` of
+    /// `UpgradeOptions` or the health check passed to `upgrade_canister_with_rollback`.
+    Verifying,
 }
 
 #[derive(Debug, Clone)]
 pub enum UpgradeErrorReason {
     RetryError(RetryError),
-    ConcurrentChangeDetected,
+    /// Another upgrade of the target by this canister holds the target's `UpgradeLock`. Reports
+    /// the holder.
+    UpgradeInProgress(InFlightUpgrade),
+    /// Someone else changed the target while it was being upgraded; see the report for details.
+    ConcurrentChangeDetected(ConcurrentChange),
+    ChunkUploadFailed(ChunkUploadError),
+    /// `install_canister` was called on a canister that already has a module installed.
+    ModuleAlreadyInstalled,
+    /// The argument failed the validation requested by the `validate_arg` upgrade option.
+    InvalidArgument(ArgValidationError),
+    /// The new module failed the `module_checks` upgrade option.
+    ModuleCheckFailed(ModuleCheckError),
+    /// The new module failed the `interface_check` upgrade option.
+    InterfaceCheckFailed(InterfaceCheckError),
+    /// The upgraded target failed the health check, with the given message.
+    HealthCheckFailed(String),
+    /// The stopped target didn't satisfy the `precondition` upgrade option, so it wasn't upgraded.
+    /// Reports the target's actual module hash and `total_num_changes`.
+    PreconditionFailed {
+        actual_hash: Option<Vec<u8>>,
+        actual_version: u64,
+    },
+    /// The target couldn't be stopped.
+    StopFailed {
+        /// Why the last attempt to stop the target failed.
+        error: RetryError,
+        /// Whether the target was still `Stopping` when we gave up. Stopping only completes once
+        /// all of the target's open call contexts are closed, so it can take arbitrarily long,
+        /// e.g., if the target awaits responses from unresponsive canisters.
+        stuck: bool,
+        /// The target's status once we gave up (and restarted it, if the
+        /// `restart_on_stop_failure` option is set), or `None` if it couldn't be determined.
+        final_status: Option<CanisterStatusType>,
+    },
 }
 
-/// Errors returned by `upgrade_canister`.
+/// Errors returned by `upgrade_canister`, `install_canister` and `reinstall_canister`.
 #[derive(Debug, Clone)]
 pub struct UpgradeError {
     pub stage: UpgradeStage,
     pub reason: UpgradeErrorReason,
 }
 
+/// Options for `upgrade_canister`.
+///
+/// The defaults correspond to a plain upgrade; use `..Default::default()` to only set some of
+/// the options.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct UpgradeOptions {
+    /// Skip the `pre_upgrade` hook of the currently installed module. Useful to recover a
+    /// canister whose `pre_upgrade` hook traps, at the cost of losing any state that the hook
+    /// would have saved to stable memory.
+    pub skip_pre_upgrade: bool,
+    /// Whether to keep or replace the WASM heap memory. Canisters using enhanced orthogonal
+    /// persistence must be upgraded with `WasmMemoryPersistence::Keep`. If `None`, the system
+    /// default (`Replace`) is used.
+    pub wasm_memory_persistence: Option<WasmMemoryPersistence>,
+    /// A check of the target after it has been upgraded and restarted. If the check fails, the
+    /// upgrade fails at the `Verifying` stage. Note that the target is left running the new
+    /// version in that case; use `upgrade_canister_with_rollback` to restore the old version.
+    pub health_check: Option<HealthCheck>,
+    /// Skip the upgrade if the target is already running the new module, avoiding the downtime
+    /// of a no-op upgrade. See `SkipIfUpToDate`.
+    pub skip_if_up_to_date: Option<SkipIfUpToDate>,
+    /// Only upgrade the target if it's in the given state. See `UpgradePrecondition`.
+    pub precondition: Option<UpgradePrecondition>,
+    /// If the target can't be stopped, start it again, instead of leaving it `Stopping` (where it
+    /// rejects new calls) or stopped. See `UpgradeErrorReason::StopFailed`.
+    pub restart_on_stop_failure: bool,
+    /// Relay the management canister calls through the given proxy, for targets that are
+    /// controlled by the proxy rather than by this canister. Modules that are installed from a
+    /// chunk store require the proxy to also control the store. See `ManagementProxy`.
+    ///
+    /// Note that the calls to the proxy are unbounded-wait calls, so an open call to the proxy
+    /// blocks this canister from being stopped, e.g., for its own upgrade, until the proxy
+    /// responds.
+    pub proxy: Option<ManagementProxy>,
+    /// The principals whose deployments of the new module count as our own when checking the
+    /// target's history for concurrent changes, e.g., a controller that forwards our calls. If
+    /// empty, only deployments made by the `proxy` (or, without a proxy, by this canister) count.
+    pub expected_origins: Vec<Principal>,
+    /// Validate the argument against the new module before touching the target, failing at the
+    /// `PreFlight` stage if it's invalid. See `validate_arg` for what is checked.
+    pub validate_arg: bool,
+    /// Inspect the new module before touching the target, failing at the `PreFlight` stage if it
+    /// doesn't meet the requirements. See `ModuleChecks`.
+    pub module_checks: Option<ModuleChecks>,
+    /// Check that the new module's Candid interface is compatible with the one of the module that
+    /// the target runs, before touching the target. See `InterfaceCheck`.
+    pub interface_check: Option<InterfaceCheck>,
+    /// If another upgrade of the target by this canister is in progress, wait for it to finish
+    /// (behind any other waiting upgrades), instead of failing right away at the `Locking` stage.
+    /// Waiting counts against `stop_trying`. See `UpgradeLock`.
+    pub wait_for_lock: bool,
+}
+
+/// A condition on the target's state, checked after the target has been stopped and before the
+/// new module is installed, for compare-and-swap semantics: the upgrade only replaces the
+/// expected version of the target.
+///
+/// Once the target is stopped, its module and version can only change through its controllers.
+/// Unlike the detection of concurrent changes, which only kicks in when the outcome of an
+/// installation attempt is unknown, the precondition thus reliably prevents overwriting changes
+/// made by others before the upgrade (short of changes made between the check and the
+/// installation). If the condition doesn't hold, the upgrade fails at the `ObtainingInfo` stage
+/// with `UpgradeErrorReason::PreconditionFailed`, and the target is left stopped, like after any
+/// other failure.
+///
+/// All the fields that are set must match.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct UpgradePrecondition {
+    /// The hash of the module that the target must be running; `Some(None)` requires the target to
+    /// be empty.
+    pub module_hash: Option<Option<Vec<u8>>>,
+    /// The target's `total_num_changes` (as reported by `canister_info`).
+    pub total_num_changes: Option<u64>,
+}
+
+impl UpgradePrecondition {
+    fn check(&self, info: &CanisterInfoResult) -> Result<(), UpgradeError> {
+        let hash_matches = self
+            .module_hash
+            .as_ref()
+            .is_none_or(|hash| *hash == info.module_hash);
+        let version_matches = self
+            .total_num_changes
+            .is_none_or(|version| version == info.total_num_changes);
+        if hash_matches && version_matches {
+            Ok(())
+        } else {
+            Err(UpgradeError {
+                stage: UpgradeStage::ObtainingInfo,
+                reason: UpgradeErrorReason::PreconditionFailed {
+                    actual_hash: info.module_hash.clone(),
+                    actual_version: info.total_num_changes,
+                },
+            })
+        }
+    }
+}
+
+/// When to consider the target up to date, for the `skip_if_up_to_date` upgrade option.
+///
+/// In either case, the target must also be running; a stopped target is upgraded (and thus
+/// restarted) even if it already has the new module, e.g., because a previous upgrade installed
+/// the module but failed to restart the target.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum SkipIfUpToDate {
+    /// The target's module hash equals the hash of the new module.
+    ModuleMatches,
+    /// The target's module hash equals the hash of the new module, and the module was deployed
+    /// with the same argument. The canister history doesn't record arguments, so the caller has to
+    /// provide the SHA-256 hash of the argument used for the installed module, which is compared
+    /// to the hash of the new argument.
+    ModuleAndArgMatch { installed_arg_hash: Vec<u8> },
+}
+
+/// The result of a successful call to `upgrade_canister`.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpgradeOutcome {
+    /// The target was upgraded to the new module.
+    Upgraded,
+    /// The target was already running the new module, and was left untouched, as requested by the
+    /// `skip_if_up_to_date` option.
+    AlreadyUpToDate,
+}
+
+impl UpgradeOptions {
+    fn install_mode(&self) -> CanisterInstallMode {
+        if !self.skip_pre_upgrade && self.wasm_memory_persistence.is_none() {
+            CanisterInstallMode::Upgrade(None)
+        } else {
+            CanisterInstallMode::Upgrade(Some(UpgradeFlags {
+                skip_pre_upgrade: Some(self.skip_pre_upgrade),
+                wasm_memory_persistence: self.wasm_memory_persistence,
+            }))
+        }
+    }
+}
+
 /// Holds the meta-information needed for a chunked WASM install.
-#[derive(Debug, Clone)]
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
 pub struct ChunkedModule {
     /// SHA-256 hash of the entire WASM to be installed.
     pub wasm_module_hash: Vec<u8>,
@@ -59,138 +280,595 @@ pub struct ChunkedModule {
     pub chunk_hashes_list: Vec<Vec<u8>>,
 }
 
+/// The maximum size of an `install_code` message, including the module and the install argument.
+pub const MAX_INSTALL_MESSAGE_SIZE: usize = 2 * 1024 * 1024;
+
+/// Headroom for the Candid encoding of the remaining `install_code` arguments.
+const INSTALL_MESSAGE_OVERHEAD: usize = 1024;
+
 /// The WASM to be installed.
-#[derive(Debug, Clone)]
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
 pub enum WasmModule {
     /// A module < 2MB that can be installed in a single message
     Bytes(Vec<u8>),
-    /// A module > 2MB that must be installed in chunks. Chunks are assumed to already have been uploaded.
+    /// A module > 2MB that must be installed in chunks. Chunks are assumed to already have been
+    /// uploaded, e.g., using `upload_chunks`.
     ChunkedModule(ChunkedModule),
+    /// A module of any size, possibly gzipped. If the module and the install argument fit into a
+    /// single install message, it's installed like `Bytes`. Otherwise, it's first uploaded into
+    /// the chunk store of `store_canister_id` (the target canister itself if `None`), and then
+    /// installed like `ChunkedModule`. Use `WasmModule::from_bytes` to construct.
+    Auto {
+        bytes: Vec<u8>,
+        store_canister_id: Option<CanisterId>,
+    },
 }
 
-enum VersionChangeCheck {
+impl WasmModule {
+    /// Creates a module from the raw (possibly gzipped) WASM bytes, choosing between a
+    /// single-message and a chunked install automatically, depending on the size of the module
+    /// and the install argument.
+    ///
+    /// # Arguments
+    /// * `bytes` - The WASM module
+    /// * `store_canister_id` - The canister whose chunk store to use if the module has to be
+    ///   installed in chunks. Must be on the same subnet as the target canister. If `None`, the
+    ///   target canister's own chunk store is used.
+    pub fn from_bytes(bytes: Vec<u8>, store_canister_id: Option<CanisterId>) -> Self {
+        WasmModule::Auto {
+            bytes,
+            store_canister_id,
+        }
+    }
+
+    /// The hash of the module, as reported by `canister_info` once installed.
+    fn module_hash(&self) -> Vec<u8> {
+        match self {
+            WasmModule::Bytes(bytes) | WasmModule::Auto { bytes, .. } => {
+                Sha256::digest(bytes).to_vec()
+            }
+            WasmModule::ChunkedModule(chunked) => chunked.wasm_module_hash.clone(),
+        }
+    }
+}
+
+/// Fits the module and install argument into a single message if possible, and uploads the
+/// module into a chunk store otherwise. The uploads are relayed through the target's proxy, if
+/// any.
+async fn prepare_module<P>(
+    target: Target<'_>,
+    wasm_module: WasmModule,
+    arg: &[u8],
+    stop_trying: &mut P,
+) -> Result<WasmModule, ChunkUploadError>
+where
+    P: FnMut() -> bool,
+{
+    match wasm_module {
+        WasmModule::Auto {
+            bytes,
+            store_canister_id,
+        } => {
+            if bytes.len() + arg.len() + INSTALL_MESSAGE_OVERHEAD <= MAX_INSTALL_MESSAGE_SIZE {
+                Ok(WasmModule::Bytes(bytes))
+            } else {
+                let store = target.with_id(store_canister_id.unwrap_or(target.id));
+                chunks::upload_chunks_to(store, &bytes, stop_trying)
+                    .await
+                    .map(WasmModule::ChunkedModule)
+            }
+        }
+        module => Ok(module),
+    }
+}
+
+/// The outcome of checking whether an installation attempt with an unknown outcome went through.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum VersionChangeCheck {
     /// The version hasn't changed. The upgrade failed and can be retried.
     NoChange,
     /// The version has changed in the expected way. The upgrade succeeded.
     UpgradeSucceeded,
     /// A concurrent change was detected. The upgrade shouldn't be retried.
-    ConcurrentChangeDetected,
+    ConcurrentChangeDetected(ConcurrentChange),
 }
 
+/// Checks whether an installation attempt with an unknown outcome went through.
+///
+/// Fetches the changes made to the target since `old_version` from its canister history, and
+/// classifies them. The attempt went through if the only change is our own deployment, and
+/// failed if there are no changes at all. Anything else is reported as a concurrent change: a
+/// deployment by someone else (or of a different module), a change of controllers, an uninstall
+/// or a snapshot load, more changes than the history retains, or the target no longer being
+/// stopped.
+///
+/// Note that the canister history records only the mode of a deployment (e.g., `upgrade`), not
+/// the `UpgradeFlags` it was made with. Upgrades with `skip_pre_upgrade` or
+/// `wasm_memory_persistence` set are thus recognized by the mode and the module hash just like
+/// plain upgrades. This is sufficient, since the management canister either applies an upgrade
+/// with all of the requested flags, or doesn't apply it at all.
 async fn version_change_check(
-    target_id: CanisterId,
+    target: Target<'_>,
+    mode: CodeDeploymentMode,
     wasm_module: &WasmModule,
     old_version: u64,
     stop_trying: &mut impl FnMut() -> bool,
 ) -> Result<VersionChangeCheck, RetryError> {
-    let (new_version, mut recent_changes) =
-        bounded_wait_canister_info(target_id, Some(1), stop_trying)
-            .await
-            .map(|info| (info.total_num_changes, info.recent_changes))?;
-    let last_change = if let Some(change) = recent_changes.pop() {
-        change
-    } else {
-        // We asked for one recent change, and there really should be at least one,
-        // since we're in the process of upgrading the canister. So there not being
-        // a change should be unreachable, but possibly some very weird concurrent
-        // changes are going on, so we can report that.
-        return Ok(VersionChangeCheck::ConcurrentChangeDetected);
+    let info =
+        bounded_wait_canister_info(target, Some(history::MAX_RECENT_CHANGES), stop_trying).await?;
+    let num_new_changes = info.total_num_changes.saturating_sub(old_version);
+    let mut recent_changes = info.recent_changes;
+    // The recent changes are ordered oldest first, so the new ones come last
+    let first_new = recent_changes
+        .len()
+        .saturating_sub(num_new_changes.try_into().unwrap_or(usize::MAX));
+    let module_hash = wasm_module.module_hash();
+    let changes: Vec<_> = recent_changes
+        .drain(first_new..)
+        .map(|change| history::classify(change, mode, &module_hash, &target))
+        .collect();
+    let status = history::bounded_wait_status(target, stop_trying).await?;
+
+    let report = ConcurrentChange {
+        baseline_num_changes: old_version,
+        current_num_changes: info.total_num_changes,
+        unobserved_changes: num_new_changes - changes.len() as u64,
+        changes,
+        status,
     };
-    match (
-        new_version - old_version,
-        last_change.details,
-        last_change.origin,
-    ) {
-        (0, _, _) => Ok(VersionChangeCheck::NoChange),
-        (1, ChangeDetails::CodeDeployment(dep), ChangeOrigin::FromCanister(rec))
-            if rec.canister_id == canister_self() =>
-        {
-            let expected_hash: Vec<u8> = match wasm_module {
-                WasmModule::Bytes(ref wasm_bytes) => Sha256::digest(wasm_bytes).to_vec(),
-                WasmModule::ChunkedModule(ref chunked) => chunked.wasm_module_hash.clone(),
-            };
-            if dep.module_hash != expected_hash {
-                Ok(VersionChangeCheck::ConcurrentChangeDetected)
-            } else {
-                Ok(VersionChangeCheck::UpgradeSucceeded)
-            }
-        }
-        (_, _, _) => Ok(VersionChangeCheck::ConcurrentChangeDetected),
-    }
+    Ok(match report.undisturbed_deployments() {
+        Some(0) => VersionChangeCheck::NoChange,
+        Some(_) => VersionChangeCheck::UpgradeSucceeded,
+        None => VersionChangeCheck::ConcurrentChangeDetected(report),
+    })
 }
 
 /// Safely upgrade a canister to a new version, without blocking the caller from
 /// being upgraded itself.
 ///
-/// Stops, installs, and then restarts the target canister.
+/// Stops, installs, and then restarts the target canister. Modules given as `WasmModule::Auto`
+/// are uploaded into a chunk store first if they're too large to be installed in a single message.
 /// Uses bounded-wait calls under the hood, ensuring that the caller isn't blocked
-/// from upgrading itself due to open call contexts.
+/// from upgrading itself due to open call contexts. The exception is the `proxy` option: the calls
+/// to the proxy are unbounded-wait calls, so the caller can't be stopped (and thus upgraded) while
+/// one of them is open (see `ManagementProxy`).
 /// It retries any failed calls until the `stop_trying` function returns true.
 /// See the `ic-call-retry` crate for sample functions.
 ///
 /// In corner cases, it may be unknown whether the upgrade succeeded (as indicated by the
 /// `StatusUnknown` return variant).
 ///
-/// Note that this function cannot protect against concurrent upgrades of the target canister.
-/// While it can detect concurrent updates in some cases (and return an error), the detection
-/// is not bulletproof. It's the caller's responsibility to ensure that they are the sole
-/// initiator of target canister upgrades, and that this function is not called multiple times in
-/// parallel.
+/// Note that this function cannot protect against concurrent upgrades of the target canister by
+/// others. When the outcome of an installation attempt is unknown, it inspects the target's
+/// history and reports any changes made by others as a `ConcurrentChange`. But changes that happen
+/// while no such check is in progress go unnoticed. It's the caller's responsibility to ensure
+/// that they are the sole initiator of target canister upgrades. Concurrent upgrades of the same
+/// target by this canister are prevented by the target's `UpgradeLock`, which this function holds
+/// for the duration of the upgrade.
 ///
 /// # Procedure
 ///
+/// The target's `UpgradeLock` is taken first, and held until the upgrade finishes.
+///
 /// 1. **Stop** the canister C via a bounded-wait call (`SysUnknown` => retry).
 ///    - Because `stop_canister` is idempotent, we can safely retry until definite success.
 /// 2. **Obtain** the current version (`canister_info`) to record the old WASM hash and canister
-///    version.
+///    version, and check the `precondition` from the options, if any.
 /// 3. **Upgrade** the canister. If `SysUnknown` is returned, call `canister_info` again:
-///    - If the canister's version changed by 1 and the hash is the expected one, we know the upgrade went through.
-///    - If not, we retry or eventually give up as `StatusUnknown`.
+///    - If the only change since step 2 is our deployment of the expected module, we know the upgrade went through.
+///    - If there are no changes, we retry or eventually give up as `StatusUnknown`.
+///    - Otherwise, or if the canister is no longer stopped, we report a `ConcurrentChange`.
 /// 4. **Start** the canister again, also with bounded-wait calls.
+/// 5. **Verify** the canister using the `health_check` from the options, if any.
+///
+/// # Arguments
+/// * `target_id` - The canister to upgrade
+/// * `wasm_module` - The new module
+/// * `arg` - The Candid-encoded argument to the `post_upgrade` hook
+/// * `options` - Upgrade flags, such as `skip_pre_upgrade`
+/// * `stop_trying` - A function that determines when to stop retrying the calls
 ///
 /// # Returns
-/// * `Ok(())` if we can confirm a successful upgrade.
+/// * `Ok(UpgradeOutcome::Upgraded)` if we can confirm a successful upgrade.
+/// * `Ok(UpgradeOutcome::AlreadyUpToDate)` if the upgrade was skipped, as requested by the
+///   `skip_if_up_to_date` option.
 /// * `Err(UpgradeError::UpgradeFailed(...))` if the upgrade failed definitively.
 /// * `Err(UpgradeError::StatusUnknown(...))` if we cannot confirm success or failure.
 pub async fn upgrade_canister<P>(
     target_id: CanisterId,
     wasm_module: WasmModule,
     arg: Vec<u8>,
+    options: UpgradeOptions,
+    stop_trying: &mut P,
+) -> Result<UpgradeOutcome, UpgradeError>
+where
+    P: FnMut() -> bool,
+{
+    upgrade_with_progress(
+        target_id,
+        wasm_module,
+        arg,
+        &options,
+        &Progress::silent(),
+        stop_trying,
+    )
+    .await
+}
+
+/// Safely upgrade a canister like `upgrade_canister`, reporting the progress of the upgrade.
+///
+/// The `observer` is called synchronously with an `UpgradeEvent` whenever the upgrade moves to a
+/// new stage, before every call attempt, when the target's version is observed, when the outcome
+/// of an installation attempt is resolved using the version, and when the upgrade finishes.
+///
+/// # Arguments
+/// * `target_id` - The canister to upgrade
+/// * `wasm_module` - The new module
+/// * `arg` - The Candid-encoded argument to the `post_upgrade` hook
+/// * `options` - Upgrade flags, such as `skip_pre_upgrade`
+/// * `observer` - Receives the progress events
+/// * `stop_trying` - A function that determines when to stop retrying the calls
+///
+/// # Returns
+/// The same as `upgrade_canister`.
+pub async fn upgrade_canister_with_observer<O, P>(
+    target_id: CanisterId,
+    wasm_module: WasmModule,
+    arg: Vec<u8>,
+    options: UpgradeOptions,
+    observer: &mut O,
+    stop_trying: &mut P,
+) -> Result<UpgradeOutcome, UpgradeError>
+where
+    O: FnMut(UpgradeEvent),
+    P: FnMut() -> bool,
+{
+    let progress = Progress::new(observer);
+    let result = upgrade_with_progress(
+        target_id,
+        wasm_module,
+        arg,
+        &options,
+        &progress,
+        stop_trying,
+    )
+    .await;
+    progress.emit(match &result {
+        Ok(UpgradeOutcome::Upgraded) => UpgradeEvent::Succeeded,
+        Ok(UpgradeOutcome::AlreadyUpToDate) => UpgradeEvent::AlreadyUpToDate,
+        Err(error) => UpgradeEvent::Failed(error.stage),
+    });
+    result
+}
+
+async fn upgrade_with_progress<P>(
+    target_id: CanisterId,
+    wasm_module: WasmModule,
+    arg: Vec<u8>,
+    options: &UpgradeOptions,
+    progress: &Progress<'_>,
+    stop_trying: &mut P,
+) -> Result<UpgradeOutcome, UpgradeError>
+where
+    P: FnMut() -> bool,
+{
+    let _lock = lock_target(target_id, options.wait_for_lock, stop_trying).await?;
+    let on_attempt = || progress.attempt();
+    let target = Target::new(target_id, options).observed_by(&on_attempt);
+    if options.validate_arg || options.module_checks.is_some() || options.interface_check.is_some()
+    {
+        progress.enter(UpgradeStage::PreFlight);
+    }
+    pre_flight(target, &wasm_module, &arg, options, progress, stop_trying).await?;
+    if let Some(check) = &options.skip_if_up_to_date {
+        progress.enter(UpgradeStage::ObtainingInfo);
+        if is_up_to_date(target, check, &wasm_module, &arg, stop_trying).await? {
+            return Ok(UpgradeOutcome::AlreadyUpToDate);
+        }
+    }
+    deploy_canister(
+        target_id,
+        options.install_mode(),
+        wasm_module,
+        arg,
+        options,
+        progress,
+        stop_trying,
+    )
+    .await?;
+    Ok(UpgradeOutcome::Upgraded)
+}
+
+/// Runs the checks of the new module and argument requested by the options, failing at the
+/// `PreFlight` stage. A failed interface check with `warn_only` set is only reported to the
+/// observer.
+async fn pre_flight<P>(
+    target: Target<'_>,
+    wasm_module: &WasmModule,
+    arg: &[u8],
+    options: &UpgradeOptions,
+    progress: &Progress<'_>,
     stop_trying: &mut P,
 ) -> Result<(), UpgradeError>
 where
     P: FnMut() -> bool,
 {
-    // Converts a `BestEffortError` into an `UpgradeError` at a given stage.
-    let add_stage = |stage: UpgradeStage| {
-        move |error: RetryError| UpgradeError {
-            stage,
-            reason: UpgradeErrorReason::RetryError(error),
+    if let Some(checks) = &options.module_checks {
+        check_module(wasm_module, checks).map_err(|error| UpgradeError {
+            stage: UpgradeStage::PreFlight,
+            reason: UpgradeErrorReason::ModuleCheckFailed(error),
+        })?;
+    }
+    if options.validate_arg {
+        validate_arg(wasm_module, arg).map_err(|error| UpgradeError {
+            stage: UpgradeStage::PreFlight,
+            reason: UpgradeErrorReason::InvalidArgument(error),
+        })?;
+    }
+    if let Some(check) = &options.interface_check {
+        match check_interface(target, wasm_module, &check.old_interface, stop_trying).await {
+            Ok(()) => (),
+            Err(error)
+                if check.warn_only && !matches!(error, InterfaceCheckError::CheckUnavailable) =>
+            {
+                progress.emit(UpgradeEvent::InterfaceCheckFailed(format!("{:?}", error)))
+            }
+            Err(error) => {
+                return Err(UpgradeError {
+                    stage: UpgradeStage::PreFlight,
+                    reason: UpgradeErrorReason::InterfaceCheckFailed(error),
+                })
+            }
         }
-    };
+    }
+    Ok(())
+}
+
+/// Checks whether the target is running the new module, as defined by `check`. Fails at the
+/// `ObtainingInfo` stage if the target's module or status can't be obtained.
+async fn is_up_to_date<P>(
+    target: Target<'_>,
+    check: &SkipIfUpToDate,
+    wasm_module: &WasmModule,
+    arg: &[u8],
+    stop_trying: &mut P,
+) -> Result<bool, UpgradeError>
+where
+    P: FnMut() -> bool,
+{
+    if let SkipIfUpToDate::ModuleAndArgMatch { installed_arg_hash } = check {
+        if Sha256::digest(arg).as_slice() != installed_arg_hash.as_slice() {
+            return Ok(false);
+        }
+    }
+    let info = bounded_wait_canister_info(target, None, stop_trying)
+        .await
+        .map_err(add_stage(UpgradeStage::ObtainingInfo))?;
+    if info.module_hash != Some(wasm_module.module_hash()) {
+        return Ok(false);
+    }
+    let status = history::bounded_wait_status(target, stop_trying)
+        .await
+        .map_err(add_stage(UpgradeStage::ObtainingInfo))?;
+    Ok(status == CanisterStatusType::Running)
+}
+
+/// Safely install a module into an empty canister, without blocking the caller from
+/// being upgraded itself.
+///
+/// Follows the same procedure as `upgrade_canister`, but uses the `install` mode. Before
+/// installing, it checks that the target doesn't have a module installed yet, and returns
+/// `UpgradeErrorReason::ModuleAlreadyInstalled` otherwise. If the outcome of an installation
+/// attempt is unknown, the attempt is considered successful only if the canister's last change
+/// is an `install` of the expected module made by the caller.
+/// If the target is locked by another upgrade, it fails right away at the `Locking` stage.
+///
+/// # Returns
+/// * `Ok(())` if we can confirm a successful installation.
+/// * `Err(UpgradeError)` if the installation failed or its status is unknown.
+pub async fn install_canister<P>(
+    target_id: CanisterId,
+    wasm_module: WasmModule,
+    arg: Vec<u8>,
+    stop_trying: &mut P,
+) -> Result<(), UpgradeError>
+where
+    P: FnMut() -> bool,
+{
+    let _lock = lock_target(target_id, false, stop_trying).await?;
+    deploy_canister(
+        target_id,
+        CanisterInstallMode::Install,
+        wasm_module,
+        arg,
+        &UpgradeOptions::default(),
+        &Progress::silent(),
+        stop_trying,
+    )
+    .await
+}
+
+/// Safely reinstall a canister, wiping its state, without blocking the caller from
+/// being upgraded itself.
+///
+/// Follows the same procedure as `upgrade_canister`, but uses the `reinstall` mode. If the
+/// outcome of a reinstallation attempt is unknown, the attempt is considered successful only if
+/// the canister's last change is a `reinstall` of the expected module made by the caller.
+/// If the target is locked by another upgrade, it fails right away at the `Locking` stage.
+///
+/// # Returns
+/// * `Ok(())` if we can confirm a successful reinstallation.
+/// * `Err(UpgradeError)` if the reinstallation failed or its status is unknown.
+pub async fn reinstall_canister<P>(
+    target_id: CanisterId,
+    wasm_module: WasmModule,
+    arg: Vec<u8>,
+    stop_trying: &mut P,
+) -> Result<(), UpgradeError>
+where
+    P: FnMut() -> bool,
+{
+    let _lock = lock_target(target_id, false, stop_trying).await?;
+    deploy_canister(
+        target_id,
+        CanisterInstallMode::Reinstall,
+        wasm_module,
+        arg,
+        &UpgradeOptions::default(),
+        &Progress::silent(),
+        stop_trying,
+    )
+    .await
+}
+
+/// Stops the target, deploys the module with the given mode, starts the target again, and runs
+/// the health check, if any. The install mode and `skip_if_up_to_date` of the options are ignored.
+async fn deploy_canister<P>(
+    target_id: CanisterId,
+    mode: CanisterInstallMode,
+    wasm_module: WasmModule,
+    arg: Vec<u8>,
+    options: &UpgradeOptions,
+    progress: &Progress<'_>,
+    stop_trying: &mut P,
+) -> Result<(), UpgradeError>
+where
+    P: FnMut() -> bool,
+{
+    let on_attempt = || progress.attempt();
+    let target = Target::new(target_id, options).observed_by(&on_attempt);
+
+    // 0) Upload the module into a chunk store if it's too large for a single message. This is done
+    // before stopping the canister, to keep the downtime short.
+    if let WasmModule::Auto { .. } = wasm_module {
+        progress.enter(UpgradeStage::UploadingChunks);
+    }
+    let wasm_module = prepare_module(target, wasm_module, &arg, stop_trying)
+        .await
+        .map_err(|error| UpgradeError {
+            stage: UpgradeStage::UploadingChunks,
+            reason: UpgradeErrorReason::ChunkUploadFailed(error),
+        })?;
 
     // 1) Stop the canister (bounded-wait).
-    bounded_wait_stop(target_id, stop_trying)
+    progress.enter(UpgradeStage::Stopping);
+    stop_or_recover(target, options.restart_on_stop_failure, stop_trying).await?;
+
+    install_stopped(
+        target,
+        mode,
+        &wasm_module,
+        &arg,
+        options.precondition.as_ref(),
+        progress,
+        stop_trying,
+    )
+    .await?;
+
+    progress.enter(UpgradeStage::Starting);
+    bounded_wait_start(target, stop_trying)
         .await
-        .map_err(add_stage(UpgradeStage::Stopping))?;
+        .map_err(add_stage(UpgradeStage::Starting))?;
 
+    // 5) Check that the restarted target is healthy.
+    match &options.health_check {
+        Some(check) => {
+            progress.enter(UpgradeStage::Verifying);
+            health::verify(target, check, stop_trying).await
+        }
+        None => Ok(()),
+    }
+}
+
+/// Converts a `RetryError` into an `UpgradeError` at a given stage.
+fn add_stage(stage: UpgradeStage) -> impl Fn(RetryError) -> UpgradeError {
+    move |error: RetryError| UpgradeError {
+        stage,
+        reason: UpgradeErrorReason::RetryError(error),
+    }
+}
+
+/// Installs a prepared module into an already stopped canister, resolving unknown outcomes of
+/// the installation attempts using the canister history.
+async fn install_stopped<P>(
+    target: Target<'_>,
+    mode: CanisterInstallMode,
+    wasm_module: &WasmModule,
+    arg: &[u8],
+    precondition: Option<&UpgradePrecondition>,
+    progress: &Progress<'_>,
+    stop_trying: &mut P,
+) -> Result<(), UpgradeError>
+where
+    P: FnMut() -> bool,
+{
     // 2) Query the current canister version for reference.
-    let version = bounded_wait_canister_info(target_id, None, stop_trying)
+    progress.enter(UpgradeStage::ObtainingInfo);
+    let info = bounded_wait_canister_info(target, None, stop_trying)
         .await
-        .map(|info| info.total_num_changes)
         .map_err(add_stage(UpgradeStage::ObtainingInfo))?;
+    progress.emit(UpgradeEvent::ObservedVersion {
+        total_num_changes: info.total_num_changes,
+        module_hash: info.module_hash.clone(),
+    });
+    if mode == CanisterInstallMode::Install && info.module_hash.is_some() {
+        return Err(UpgradeError {
+            stage: UpgradeStage::ObtainingInfo,
+            reason: UpgradeErrorReason::ModuleAlreadyInstalled,
+        });
+    }
+    if let Some(precondition) = precondition {
+        precondition.check(&info)?;
+    }
 
+    install_from_baseline(
+        target,
+        mode,
+        wasm_module,
+        arg,
+        info.total_num_changes,
+        progress,
+        stop_trying,
+    )
+    .await
+}
+
+/// The mode recorded in the canister history for a deployment with the given install mode.
+fn deployment_mode(mode: CanisterInstallMode) -> CodeDeploymentMode {
+    match mode {
+        CanisterInstallMode::Install => CodeDeploymentMode::Install,
+        CanisterInstallMode::Reinstall => CodeDeploymentMode::Reinstall,
+        CanisterInstallMode::Upgrade(_) => CodeDeploymentMode::Upgrade,
+    }
+}
+
+/// Installs a prepared module into a stopped canister, given the canister's number of changes
+/// (`total_num_changes`) before the installation.
+async fn install_from_baseline<P>(
+    target: Target<'_>,
+    mode: CanisterInstallMode,
+    wasm_module: &WasmModule,
+    arg: &[u8],
+    version: u64,
+    progress: &Progress<'_>,
+    stop_trying: &mut P,
+) -> Result<(), UpgradeError>
+where
+    P: FnMut() -> bool,
+{
+    progress.enter(UpgradeStage::Installing);
     // 3) Install (upgrade) the new WASM. Loop until success or timeout. We can't retry directly
     // here if we don't know what happened, since installation isn't idempotent. Instead, use the
     // version number to determine if the upgrade went through.
     loop {
         let install_result = match wasm_module {
-            WasmModule::Bytes(ref wasm_bytes) => {
-                bounded_wait_install_single_chunk(target_id, wasm_bytes, &arg, stop_trying).await
+            WasmModule::Bytes(wasm_bytes) => {
+                bounded_wait_install_single_chunk(target, mode, wasm_bytes, arg, stop_trying).await
             }
-            WasmModule::ChunkedModule(ref chunked) => {
-                bounded_wait_install_chunked(target_id, chunked, &arg, stop_trying).await
+            WasmModule::ChunkedModule(chunked) => {
+                bounded_wait_install_chunked(target, mode, chunked, arg, stop_trying).await
             }
+            WasmModule::Auto { .. } => unreachable!("Modules are prepared before installation"),
         };
 
         match install_result {
@@ -201,26 +879,29 @@ where
             Err(RetryError::StatusUnknown(ErrorCause::CallFailed(rejection)))
                 if !rejection.is_clean_reject() =>
             {
-                let version_check_result =
-                    version_change_check(target_id, &wasm_module, version, stop_trying)
-                        .await
-                        .map_err(add_stage(UpgradeStage::Installing))?;
+                let version_check_result = version_change_check(
+                    target,
+                    deployment_mode(mode),
+                    wasm_module,
+                    version,
+                    stop_trying,
+                )
+                .await
+                .map_err(add_stage(UpgradeStage::Installing))?;
+                progress.emit(UpgradeEvent::VersionCheck(version_check_result.clone()));
 
                 match version_check_result {
+                    // The installation failed and the version hasn't moved, retry
                     VersionChangeCheck::NoChange => {
-                        ic_cdk::println!(
-                            "Failed to upgrade {:?} and the version hasn't moved, retrying",
-                            target_id
-                        );
                         continue;
                     }
                     VersionChangeCheck::UpgradeSucceeded => {
                         break;
                     }
-                    VersionChangeCheck::ConcurrentChangeDetected => {
+                    VersionChangeCheck::ConcurrentChangeDetected(report) => {
                         return Err(UpgradeError {
                             stage: UpgradeStage::Installing,
-                            reason: UpgradeErrorReason::ConcurrentChangeDetected,
+                            reason: UpgradeErrorReason::ConcurrentChangeDetected(report),
                         });
                     }
                 }
@@ -229,21 +910,19 @@ where
         }
     }
 
-    bounded_wait_start(target_id, stop_trying)
-        .await
-        .map_err(add_stage(UpgradeStage::Starting))
+    Ok(())
 }
 
 /// Stop a canister with best-effort calls until success or timeout.
-async fn bounded_wait_stop<P>(target_id: Principal, stop_trying: &mut P) -> Result<(), RetryError>
+async fn bounded_wait_stop<P>(target: Target<'_>, stop_trying: &mut P) -> Result<(), RetryError>
 where
     P: FnMut() -> bool,
 {
     let args = StopCanisterArgs {
-        canister_id: target_id,
+        canister_id: target.id,
     };
     Ok(call_idempotent_method_with_retry(
-        Call::bounded_wait(Principal::management_canister(), "stop_canister").with_arg(&args),
+        target.management_call("stop_canister", &args),
         stop_trying,
     )
     .await?
@@ -251,16 +930,71 @@ where
     .unwrap())
 }
 
+/// The number of retries for each call made to recover the target after we gave up stopping it.
+/// The caller's `stop_trying` has run out by then, so the recovery uses a small budget of its own.
+const STOP_RECOVERY_MAX_RETRIES: u32 = 3;
+
+/// Stops the target like `bounded_wait_stop`. If that fails, determines the target's status and,
+/// if `restart` is set, starts the target again, so that it isn't left stopping or stopped.
+///
+/// Fails with `UpgradeErrorReason::StopFailed` at the `Stopping` stage.
+async fn stop_or_recover<P>(
+    target: Target<'_>,
+    restart: bool,
+    stop_trying: &mut P,
+) -> Result<(), UpgradeError>
+where
+    P: FnMut() -> bool,
+{
+    let error = match bounded_wait_stop(target, stop_trying).await {
+        Ok(()) => return Ok(()),
+        Err(error) => error,
+    };
+
+    let status_at_abort = history::bounded_wait_status(
+        target,
+        &mut when_max_retries_reached(STOP_RECOVERY_MAX_RETRIES),
+    )
+    .await
+    .ok();
+    let final_status = if restart && status_at_abort != Some(CanisterStatusType::Running) {
+        // Starting a `Stopping` canister cancels the stop. Starting a running one is a no-op, so
+        // we also try it if the status is unknown.
+        let _ = bounded_wait_start(
+            target,
+            &mut when_max_retries_reached(STOP_RECOVERY_MAX_RETRIES),
+        )
+        .await;
+        history::bounded_wait_status(
+            target,
+            &mut when_max_retries_reached(STOP_RECOVERY_MAX_RETRIES),
+        )
+        .await
+        .ok()
+    } else {
+        status_at_abort
+    };
+
+    Err(UpgradeError {
+        stage: UpgradeStage::Stopping,
+        reason: UpgradeErrorReason::StopFailed {
+            error,
+            stuck: status_at_abort == Some(CanisterStatusType::Stopping),
+            final_status,
+        },
+    })
+}
+
 /// Start a canister with best-effort calls until success or timeout.
-async fn bounded_wait_start<P>(target_id: CanisterId, stop_trying: &mut P) -> Result<(), RetryError>
+async fn bounded_wait_start<P>(target: Target<'_>, stop_trying: &mut P) -> Result<(), RetryError>
 where
     P: FnMut() -> bool,
 {
     let args = StartCanisterArgs {
-        canister_id: target_id,
+        canister_id: target.id,
     };
     Ok(call_idempotent_method_with_retry(
-        Call::bounded_wait(Principal::management_canister(), "start_canister").with_arg(&args),
+        target.management_call("start_canister", &args),
         stop_trying,
     )
     .await?
@@ -270,7 +1004,7 @@ where
 
 /// Retrieve canister info (including module hash) with best-effort calls.
 async fn bounded_wait_canister_info<P>(
-    target_id: CanisterId,
+    target: Target<'_>,
     num_requested_changes: Option<u64>,
     stop_trying: &mut P,
 ) -> Result<CanisterInfoResult, RetryError>
@@ -278,12 +1012,12 @@ where
     P: FnMut() -> bool,
 {
     let arg = CanisterInfoArgs {
-        canister_id: target_id,
+        canister_id: target.id,
         num_requested_changes,
     };
 
     Ok(call_idempotent_method_with_retry(
-        Call::bounded_wait(Principal::management_canister(), "canister_info").with_arg(&arg),
+        target.management_call("canister_info", &arg),
         stop_trying,
     )
     .await?
@@ -295,7 +1029,8 @@ where
 /// Since code installation isn't idempotent, we don't just retry on `SysUnknown`.
 /// Rather, we leave it up to the caller to handle.
 async fn bounded_wait_install_single_chunk<P>(
-    target_id: CanisterId,
+    target: Target<'_>,
+    mode: CanisterInstallMode,
     wasm_bytes: &[u8],
     arg: &[u8],
     stop_trying: &mut P,
@@ -304,15 +1039,14 @@ where
     P: FnMut() -> bool,
 {
     let install_args = InstallCodeArgs {
-        mode: CanisterInstallMode::Upgrade(None),
-        canister_id: target_id,
+        mode,
+        canister_id: target.id,
         wasm_module: wasm_bytes.to_vec(),
         arg: arg.to_vec(),
     };
 
     Ok(call_nonidempotent_method_with_retry(
-        Call::bounded_wait(Principal::management_canister(), "install_code")
-            .with_arg(&install_args),
+        target.management_call("install_code", &install_args),
         stop_trying,
     )
     .await?
@@ -320,45 +1054,11 @@ where
     .expect("Candid decoding failed"))
 }
 
-#[allow(dead_code)]
-async fn upload_chunks(
-    store_canister_id: CanisterId,
-    chunks: Vec<Vec<u8>>,
-    deadline: &Deadline,
-) -> Result<(), RetryError> {
-    let call = Call::bounded_wait(Principal::management_canister(), "clear_chunk_store").with_arg(
-        &ClearChunkStoreArgs {
-            canister_id: store_canister_id,
-        },
-    );
-
-    let mut retry_fn = when_out_of_time_or_stopping(deadline);
-    let _: () = call_idempotent_method_with_retry(call, &mut retry_fn)
-        .await?
-        .candid()
-        .unwrap();
-
-    for chunk in chunks {
-        let chunk_install_args = UploadChunkArgs {
-            canister_id: store_canister_id,
-            chunk,
-        };
-
-        let call = Call::bounded_wait(Principal::management_canister(), "upload_chunk")
-            .with_arg(&chunk_install_args);
-        let mut retry_fn = when_out_of_time_or_stopping(deadline);
-        let _: () = call_idempotent_method_with_retry(call, &mut retry_fn)
-            .await?
-            .candid()
-            .unwrap();
-    }
-    Ok(())
-}
-
 /// Install a large (>2MB) WASM by referencing pre-uploaded chunks, via `install_chunked_code`.
 /// Chunks are assumed to already have been uploaded
 async fn bounded_wait_install_chunked<P>(
-    target_id: CanisterId,
+    target: Target<'_>,
+    mode: CanisterInstallMode,
     chunked: &ChunkedModule,
     arg: &[u8],
     stop_trying: &mut P,
@@ -367,8 +1067,8 @@ where
     P: FnMut() -> bool,
 {
     let install_args = InstallChunkedCodeArgs {
-        mode: CanisterInstallMode::Upgrade(None),
-        target_canister: target_id,
+        mode,
+        target_canister: target.id,
         store_canister: Some(chunked.store_canister_id),
         chunk_hashes_list: chunked
             .chunk_hashes_list
@@ -379,8 +1079,7 @@ where
         arg: arg.to_vec(),
     };
 
-    let install_call = Call::bounded_wait(Principal::management_canister(), "install_chunked_code")
-        .with_arg(&install_args);
+    let install_call = target.management_call("install_chunked_code", &install_args);
     let res = call_nonidempotent_method_with_retry(install_call, stop_trying).await?;
     Ok(res.candid().unwrap())
 }
@@ -0,0 +1,281 @@
+use crate::proxy::Target;
+use crate::{bounded_wait_canister_info, bounded_wait_stop, CanisterId};
+use candid::{CandidType, Principal};
+use ic_call_retry::{
+    call_idempotent_method_with_retry, call_nonidempotent_method_with_retry, Call, ErrorCause,
+    RetryError,
+};
+use ic_cdk::api::{canister_version, cost_create_canister};
+use ic_cdk::call::{CallFailed, RejectCode};
+use ic_management_canister_types::{
+    CanisterSettings, CreateCanisterArgs, CreateCanisterResult, DeleteCanisterArgs,
+    DepositCyclesArgs, UninstallCodeArgs, UpdateSettingsArgs,
+};
+use serde::{Deserialize, Serialize};
+
+/// The lifecycle operation during which an error occurred.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LifecycleOperation {
+    CreatingCanister,
+    UpdatingSettings,
+    /// Reading the controllers of the canister, in `change_controllers`.
+    ObtainingInfo,
+    DepositingCycles,
+    UninstallingCode,
+    /// Stopping the canister before deleting it, in `delete_canister`.
+    Stopping,
+    DeletingCanister,
+}
+
+/// Errors returned by the lifecycle functions `create_canister`, `update_settings`,
+/// `deposit_cycles`, `uninstall_code` and `delete_canister`.
+///
+/// A `RetryError::StatusUnknown` error means that the operation may or may not have taken effect.
+#[derive(Debug, Clone)]
+pub struct LifecycleError {
+    pub operation: LifecycleOperation,
+    pub error: RetryError,
+}
+
+fn during(operation: LifecycleOperation) -> impl Fn(RetryError) -> LifecycleError {
+    move |error| LifecycleError { operation, error }
+}
+
+/// Creates a new canister, attaching the cycles needed for the creation plus `extra_cycles`,
+/// which become the new canister's balance.
+///
+/// Creating a canister isn't idempotent, and unlike with the other lifecycle operations, its
+/// outcome can't be determined after the fact: there's no way to list the canisters created by
+/// a caller. A creation with an unknown outcome may thus leak the new canister and the attached
+/// cycles. To avoid that, this function uses unbounded-wait calls, which always get a definite
+/// response; the management canister responds to `create_canister` without calling other
+/// canisters, so the wait is short. Only clean transient failures are retried.
+///
+/// # Arguments
+/// * `settings` - The settings of the new canister; the default makes the caller its only
+///   controller
+/// * `extra_cycles` - The cycles to deposit into the new canister, on top of the creation fee
+/// * `stop_trying` - A function that determines when to stop (re)trying the call
+///
+/// # Returns
+/// * `Ok(CanisterId)` with the ID of the new canister
+/// * `Err(LifecycleError)` at the `CreatingCanister` operation if the canister wasn't created.
+pub async fn create_canister<P>(
+    settings: Option<CanisterSettings>,
+    extra_cycles: u128,
+    stop_trying: &mut P,
+) -> Result<CanisterId, LifecycleError>
+where
+    P: FnMut() -> bool,
+{
+    let args = CreateCanisterArgs {
+        settings,
+        sender_canister_version: Some(canister_version()),
+    };
+    let result: CreateCanisterResult = call_nonidempotent_method_with_retry(
+        Call::unbounded_wait(Principal::management_canister(), "create_canister")
+            .with_arg(&args)
+            .with_cycles(cost_create_canister() + extra_cycles),
+        stop_trying,
+    )
+    .await
+    .map_err(during(LifecycleOperation::CreatingCanister))?
+    .candid()
+    .expect("Candid decoding failed");
+    Ok(result.canister_id)
+}
+
+/// Updates the settings of the target with bounded-wait calls.
+///
+/// Setting the same settings again has no further effect, so the call is retried, also when its
+/// outcome is unknown. Note that a retry may overwrite a change made by someone else in the
+/// meantime. Settings that are `None` are left unchanged.
+///
+/// # Arguments
+/// * `target_id` - The canister whose settings to update
+/// * `settings` - The new settings
+/// * `stop_trying` - A function that determines when to stop (re)trying the call
+///
+/// # Returns
+/// * `Ok(())` if the settings were updated
+/// * `Err(LifecycleError)` at the `UpdatingSettings` operation otherwise.
+pub async fn update_settings<P>(
+    target_id: CanisterId,
+    settings: CanisterSettings,
+    stop_trying: &mut P,
+) -> Result<(), LifecycleError>
+where
+    P: FnMut() -> bool,
+{
+    let args = UpdateSettingsArgs {
+        canister_id: target_id,
+        settings,
+        sender_canister_version: Some(canister_version()),
+    };
+    let _: () = call_idempotent_method_with_retry(
+        Call::bounded_wait(Principal::management_canister(), "update_settings").with_arg(&args),
+        stop_trying,
+    )
+    .await
+    .map_err(during(LifecycleOperation::UpdatingSettings))?
+    .candid()
+    .expect("Candid decoding failed");
+    Ok(())
+}
+
+/// Deposits cycles into the target.
+///
+/// Depositing isn't idempotent, and the cycles attached to a bounded-wait call that times out
+/// may be lost. Like `create_canister`, this function therefore uses unbounded-wait calls, and
+/// only retries clean transient failures.
+///
+/// # Arguments
+/// * `target_id` - The canister to deposit the cycles into
+/// * `cycles` - The amount of cycles to deposit
+/// * `stop_trying` - A function that determines when to stop (re)trying the call
+///
+/// # Returns
+/// * `Ok(())` if the cycles were deposited
+/// * `Err(LifecycleError)` at the `DepositingCycles` operation otherwise; the cycles are refunded
+///   unless the error is `RetryError::StatusUnknown`.
+pub async fn deposit_cycles<P>(
+    target_id: CanisterId,
+    cycles: u128,
+    stop_trying: &mut P,
+) -> Result<(), LifecycleError>
+where
+    P: FnMut() -> bool,
+{
+    let args = DepositCyclesArgs {
+        canister_id: target_id,
+    };
+    let _: () = call_nonidempotent_method_with_retry(
+        Call::unbounded_wait(Principal::management_canister(), "deposit_cycles")
+            .with_arg(&args)
+            .with_cycles(cycles),
+        stop_trying,
+    )
+    .await
+    .map_err(during(LifecycleOperation::DepositingCycles))?
+    .candid()
+    .expect("Candid decoding failed");
+    Ok(())
+}
+
+/// Uninstalls the target's code with bounded-wait calls, removing its module and state.
+///
+/// Uninstalling an already empty canister has no further effect, so the call is retried, also
+/// when its outcome is unknown. Each successful attempt is recorded in the canister history,
+/// though.
+///
+/// # Arguments
+/// * `target_id` - The canister to uninstall
+/// * `stop_trying` - A function that determines when to stop (re)trying the call
+///
+/// # Returns
+/// * `Ok(())` if the code was uninstalled
+/// * `Err(LifecycleError)` at the `UninstallingCode` operation otherwise.
+pub async fn uninstall_code<P>(
+    target_id: CanisterId,
+    stop_trying: &mut P,
+) -> Result<(), LifecycleError>
+where
+    P: FnMut() -> bool,
+{
+    let args = UninstallCodeArgs {
+        canister_id: target_id,
+        sender_canister_version: Some(canister_version()),
+    };
+    let _: () = call_idempotent_method_with_retry(
+        Call::bounded_wait(Principal::management_canister(), "uninstall_code").with_arg(&args),
+        stop_trying,
+    )
+    .await
+    .map_err(during(LifecycleOperation::UninstallingCode))?
+    .candid()
+    .expect("Candid decoding failed");
+    Ok(())
+}
+
+/// Stops and then deletes the target with bounded-wait calls.
+///
+/// Deleting isn't idempotent: retrying a deletion that went through fails, as the target no
+/// longer exists. When the outcome of a deletion attempt is unknown, we thus check whether the
+/// target still exists using `canister_info`, and only retry if it does (and `stop_trying`
+/// allows it). If a later attempt fails, we check again, as the earlier attempt may have gone
+/// through in the meantime.
+///
+/// # Arguments
+/// * `target_id` - The canister to delete
+/// * `stop_trying` - A function that determines when to stop (re)trying the calls
+///
+/// # Returns
+/// * `Ok(())` if the target was deleted
+/// * `Err(LifecycleError)` at the `Stopping` or `DeletingCanister` operation otherwise. The
+///   target may be left stopped if the deletion fails.
+pub async fn delete_canister<P>(
+    target_id: CanisterId,
+    stop_trying: &mut P,
+) -> Result<(), LifecycleError>
+where
+    P: FnMut() -> bool,
+{
+    bounded_wait_stop(Target::direct(target_id), stop_trying)
+        .await
+        .map_err(during(LifecycleOperation::Stopping))?;
+
+    let args = DeleteCanisterArgs {
+        canister_id: target_id,
+    };
+    let mut had_unknown_outcome = false;
+    loop {
+        match call_nonidempotent_method_with_retry(
+            Call::bounded_wait(Principal::management_canister(), "delete_canister").with_arg(&args),
+            stop_trying,
+        )
+        .await
+        {
+            Ok(_) => return Ok(()),
+            Err(RetryError::StatusUnknown(cause)) => {
+                had_unknown_outcome = true;
+                match exists(target_id, stop_trying).await {
+                    Some(false) => return Ok(()),
+                    // The previous attempt may still go through, so giving up now leaves the
+                    // outcome unknown
+                    Some(true) if !stop_trying() => continue,
+                    Some(true) | None => {
+                        return Err(LifecycleError {
+                            operation: LifecycleOperation::DeletingCanister,
+                            error: RetryError::StatusUnknown(cause),
+                        })
+                    }
+                }
+            }
+            Err(error) => {
+                // An earlier attempt with an unknown outcome may have deleted the target in the
+                // meantime, which makes this attempt fail
+                if had_unknown_outcome && exists(target_id, stop_trying).await == Some(false) {
+                    return Ok(());
+                }
+                return Err(LifecycleError {
+                    operation: LifecycleOperation::DeletingCanister,
+                    error,
+                });
+            }
+        }
+    }
+}
+
+/// Whether the target exists, or `None` if that couldn't be determined.
+async fn exists<P>(target_id: CanisterId, stop_trying: &mut P) -> Option<bool>
+where
+    P: FnMut() -> bool,
+{
+    match bounded_wait_canister_info(Target::direct(target_id), Some(0), stop_trying).await {
+        Ok(_) => Some(true),
+        Err(RetryError::CallFailed(ErrorCause::CallFailed(CallFailed::CallRejected(
+            rejection,
+        )))) if rejection.reject_code() == Ok(RejectCode::DestinationInvalid) => Some(false),
+        Err(_) => None,
+    }
+}
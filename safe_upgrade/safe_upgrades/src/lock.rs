@@ -0,0 +1,209 @@
+use crate::{CanisterId, UpgradeError, UpgradeErrorReason, UpgradeStage};
+use candid::CandidType;
+use ic_call_retry::yield_execution;
+use ic_cdk::api::time;
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, VecDeque};
+
+/// An upgrade that currently holds the lock on its target.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InFlightUpgrade {
+    pub target_id: CanisterId,
+    /// When the lock was acquired, in nanoseconds since the UNIX epoch.
+    pub locked_at_nanos: u64,
+}
+
+/// The lock on a single target: its holder, and the tickets of the waiters in the order in which
+/// they started waiting.
+struct TargetLock {
+    holder: InFlightUpgrade,
+    holder_ticket: u64,
+    waiting: VecDeque<u64>,
+}
+
+thread_local! {
+    static LOCKS: RefCell<BTreeMap<CanisterId, TargetLock>> =
+        const { RefCell::new(BTreeMap::new()) };
+    static NEXT_TICKET: Cell<u64> = const { Cell::new(0) };
+}
+
+/// A place in the line for a target's lock, which is either holding the lock or waiting for it.
+/// Dropping the ticket leaves the line, handing the lock to the next waiter if we held it.
+#[derive(Debug)]
+struct Ticket {
+    target_id: CanisterId,
+    number: u64,
+}
+
+impl Ticket {
+    fn new(target_id: CanisterId) -> Self {
+        let number = NEXT_TICKET.with(|next| next.replace(next.get() + 1));
+        Self { target_id, number }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        LOCKS.with(|locks| {
+            let mut locks = locks.borrow_mut();
+            let released = match locks.get_mut(&self.target_id) {
+                Some(lock) if lock.holder_ticket == self.number => {
+                    match lock.waiting.pop_front() {
+                        // The time can be read in any context, including the clean-up after a
+                        // trap, which is when the tickets of trapped upgrades are dropped
+                        Some(next) => {
+                            lock.holder_ticket = next;
+                            lock.holder = InFlightUpgrade {
+                                target_id: self.target_id,
+                                locked_at_nanos: time(),
+                            };
+                            false
+                        }
+                        None => true,
+                    }
+                }
+                Some(lock) => {
+                    lock.waiting.retain(|number| *number != self.number);
+                    false
+                }
+                None => false,
+            };
+            if released {
+                locks.remove(&self.target_id);
+            }
+        })
+    }
+}
+
+/// Exclusive permission to upgrade a target, from the canister-local upgrade registry.
+///
+/// The upgrade functions of this crate (including `UpgradeJob`s and `Rollout`s) take the lock on
+/// their target before touching it, so that there's at most one upgrade in progress per target
+/// in this canister. If the target is already locked, they fail with
+/// `UpgradeErrorReason::UpgradeInProgress`, or wait for the lock if the `wait_for_lock` upgrade
+/// option is set.
+///
+/// Take the lock yourself to keep the upgrade functions away from a target while you work on it
+/// in other ways, e.g., while changing its settings. Don't call the upgrade functions for a target
+/// while holding its lock, as they'd be waiting for you.
+///
+/// The lock is released when it's dropped. This includes the case where the upgrading task traps
+/// in a later message, as the CDK then cancels the task and drops its local variables. The
+/// registry isn't persisted across upgrades of the upgrading canister itself; use `UpgradeJob`s
+/// to carry upgrades across those.
+#[derive(Debug)]
+pub struct UpgradeLock {
+    ticket: Ticket,
+}
+
+impl UpgradeLock {
+    /// Locks the target, or fails with the upgrade currently holding the lock.
+    pub fn try_acquire(target_id: CanisterId) -> Result<Self, InFlightUpgrade> {
+        LOCKS.with(|locks| {
+            let mut locks = locks.borrow_mut();
+            if let Some(lock) = locks.get(&target_id) {
+                return Err(lock.holder.clone());
+            }
+            let ticket = Ticket::new(target_id);
+            locks.insert(
+                target_id,
+                TargetLock {
+                    holder: InFlightUpgrade {
+                        target_id,
+                        locked_at_nanos: time(),
+                    },
+                    holder_ticket: ticket.number,
+                    waiting: VecDeque::new(),
+                },
+            );
+            Ok(Self { ticket })
+        })
+    }
+
+    /// Locks the target, waiting in line for the current holder and any earlier waiters to
+    /// release the lock if needed.
+    ///
+    /// The waiters get the lock in the order in which they started waiting: when the holder
+    /// releases the lock, it's handed to the first waiter, which becomes the holder reported by
+    /// `in_flight_upgrades` right away. Since a call context can't be paused on
+    /// the IC, waiting is done by yielding (see `ic_call_retry::yield_execution`), and checking
+    /// whether the lock has been handed to us once the yield returns.
+    ///
+    /// # Arguments
+    /// * `target_id` - The canister to lock
+    /// * `stop_trying` - Consulted after each yield; the function gives up its place in line
+    ///   once it returns true
+    ///
+    /// # Returns
+    /// * `Ok(UpgradeLock)` once the lock is acquired
+    /// * `Err(InFlightUpgrade)` with the current holder of the lock, if we gave up waiting.
+    pub async fn acquire<P>(
+        target_id: CanisterId,
+        stop_trying: &mut P,
+    ) -> Result<Self, InFlightUpgrade>
+    where
+        P: FnMut() -> bool,
+    {
+        let ticket = match Self::try_acquire(target_id) {
+            Ok(lock) => return Ok(lock),
+            Err(_) => Ticket::new(target_id),
+        };
+        LOCKS.with(|locks| {
+            if let Some(lock) = locks.borrow_mut().get_mut(&target_id) {
+                lock.waiting.push_back(ticket.number);
+            }
+        });
+        loop {
+            yield_execution().await;
+            let holder = LOCKS.with(|locks| {
+                let locks = locks.borrow();
+                let lock = locks
+                    .get(&target_id)
+                    .expect("A waited-for lock should stay locked until handed to us");
+                (lock.holder_ticket != ticket.number).then(|| lock.holder.clone())
+            });
+            match holder {
+                None => return Ok(Self { ticket }),
+                Some(holder) if stop_trying() => return Err(holder),
+                Some(_) => (),
+            }
+        }
+    }
+
+    /// The target that this lock is for.
+    pub fn target_id(&self) -> CanisterId {
+        self.ticket.target_id
+    }
+}
+
+/// Takes the lock on the target for one of the upgrade functions, waiting for it if requested.
+pub(crate) async fn lock_target<P>(
+    target_id: CanisterId,
+    wait: bool,
+    stop_trying: &mut P,
+) -> Result<UpgradeLock, UpgradeError>
+where
+    P: FnMut() -> bool,
+{
+    let lock = if wait {
+        UpgradeLock::acquire(target_id, stop_trying).await
+    } else {
+        UpgradeLock::try_acquire(target_id)
+    };
+    lock.map_err(|holder| UpgradeError {
+        stage: UpgradeStage::Locking,
+        reason: UpgradeErrorReason::UpgradeInProgress(holder),
+    })
+}
+
+/// Lists the upgrades that currently hold a lock, ordered by target.
+pub fn in_flight_upgrades() -> Vec<InFlightUpgrade> {
+    LOCKS.with(|locks| {
+        locks
+            .borrow()
+            .values()
+            .map(|lock| lock.holder.clone())
+            .collect()
+    })
+}
@@ -0,0 +1,141 @@
+use crate::events::ObservedCall;
+use crate::{CanisterId, UpgradeOptions};
+use candid::{encode_one, CandidType, Principal};
+use ic_call_retry::Call;
+use ic_cdk::api::{canister_self, canister_version};
+use ic_management_canister_types::ChangeOrigin;
+use serde::{Deserialize, Serialize};
+
+/// A canister that relays management canister calls on behalf of the upgrader, for targets that
+/// are controlled by the proxy rather than by the upgrader itself.
+///
+/// The proxy's `method` takes a `RelayArgs` argument. It must call `callee.method` with the raw
+/// `arg` using an unbounded-wait call, and reply with the raw reply of that call (e.g., using
+/// `ic_cdk::api::msg_reply`), or reject if that call is rejected. The proxy should only relay
+/// calls from trusted callers.
+///
+/// The calls to the proxy are unbounded-wait calls too. Had a bounded-wait call to the proxy timed
+/// out, the relayed call could still be pending, and take effect after we had checked the target's
+/// history and concluded that it didn't, e.g., installing the module again on the restarted
+/// target. With unbounded-wait calls, we learn the outcome of every relayed call instead, as the
+/// management canister always responds to the proxy. The flip side is that the upgrading canister
+/// can't be stopped while a relayed call is in flight, so the proxy must respond promptly.
+///
+/// Since the proxy sends the relayed calls, the `sender_canister_version` of the relayed calls is
+/// left unset, as we don't know the proxy's version.
+///
+/// All management canister calls of an upgrade are relayed, including the chunk uploads of a
+/// `WasmModule::Auto` module, so the proxy must control the chunk store canister too.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ManagementProxy {
+    pub proxy_id: CanisterId,
+    /// The proxy's relay method.
+    pub method: String,
+}
+
+/// The argument of a proxy's relay method; see `ManagementProxy`.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RelayArgs {
+    pub callee: Principal,
+    pub method: String,
+    /// The Candid-encoded argument of the call.
+    #[serde(with = "serde_bytes")]
+    pub arg: Vec<u8>,
+}
+
+/// The target of an upgrade, along with how to reach the management canister on its behalf,
+/// whose deployments to recognize as our own, and whom to tell about the calls made for it.
+#[derive(Clone, Copy)]
+pub(crate) struct Target<'p> {
+    pub(crate) id: CanisterId,
+    proxy: Option<&'p ManagementProxy>,
+    expected_origins: &'p [Principal],
+    on_attempt: Option<&'p dyn Fn()>,
+}
+
+impl<'p> Target<'p> {
+    /// A target that this canister controls directly.
+    pub(crate) fn direct(id: CanisterId) -> Target<'static> {
+        Target {
+            id,
+            proxy: None,
+            expected_origins: &[],
+            on_attempt: None,
+        }
+    }
+
+    /// A target reached as set by the `proxy` and `expected_origins` upgrade options.
+    pub(crate) fn new(id: CanisterId, options: &'p UpgradeOptions) -> Self {
+        Self {
+            id,
+            proxy: options.proxy.as_ref(),
+            expected_origins: &options.expected_origins,
+            on_attempt: None,
+        }
+    }
+
+    /// The same target, running `on_attempt` before each attempt of the calls made for it.
+    pub(crate) fn observed_by(self, on_attempt: &'p dyn Fn()) -> Self {
+        Self {
+            on_attempt: Some(on_attempt),
+            ..self
+        }
+    }
+
+    /// Wraps a call made for the target, such that its attempts are observed.
+    pub(crate) fn observe<C>(&self, call: C) -> ObservedCall<'p, C> {
+        ObservedCall::new(call, self.on_attempt)
+    }
+
+    /// The same route to the management canister, but for a different canister, e.g., a chunk
+    /// store.
+    pub(crate) fn with_id(self, id: CanisterId) -> Self {
+        Self { id, ..self }
+    }
+
+    /// Builds a call to the given management canister method: a bounded-wait call, or an
+    /// unbounded-wait call to the proxy if there is one (see `ManagementProxy`).
+    pub(crate) fn management_call<'m, T: CandidType>(
+        &self,
+        method: &'m str,
+        arg: &T,
+    ) -> ObservedCall<'p, Call<'m, 'static>>
+    where
+        'p: 'm,
+    {
+        let call = match self.proxy {
+            None => Call::bounded_wait(Principal::management_canister(), method).with_arg(arg),
+            Some(proxy) => {
+                Call::unbounded_wait(proxy.proxy_id, &proxy.method).with_arg(&RelayArgs {
+                    callee: Principal::management_canister(),
+                    method: method.to_string(),
+                    arg: encode_one(arg).expect("Candid encoding failed"),
+                })
+            }
+        };
+        self.observe(call)
+    }
+
+    /// The `sender_canister_version` for the management canister calls: our own version, unless
+    /// the calls are relayed through the proxy.
+    pub(crate) fn sender_canister_version(&self) -> Option<u64> {
+        self.proxy.is_none().then(canister_version)
+    }
+
+    /// Whether a change with the given origin was made by us: by one of the expected origins if
+    /// any are set, and otherwise by the proxy or, without a proxy, by this canister.
+    pub(crate) fn is_own_origin(&self, origin: &ChangeOrigin) -> bool {
+        let principal = match origin {
+            ChangeOrigin::FromUser(record) => record.user_id,
+            ChangeOrigin::FromCanister(record) => record.canister_id,
+        };
+        if self.expected_origins.is_empty() {
+            let own = self
+                .proxy
+                .map_or_else(canister_self, |proxy| proxy.proxy_id);
+            matches!(origin, ChangeOrigin::FromCanister(_)) && principal == own
+        } else {
+            self.expected_origins.contains(&principal)
+        }
+    }
+}
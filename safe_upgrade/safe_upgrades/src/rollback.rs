@@ -0,0 +1,449 @@
+use crate::events::Progress;
+use crate::health::verify;
+use crate::lock::lock_target;
+use crate::proxy::Target;
+use crate::{
+    add_stage, bounded_wait_start, bounded_wait_stop, install_stopped, is_up_to_date, pre_flight,
+    prepare_module, stop_or_recover, CanisterId, UpgradeError, UpgradeErrorReason, UpgradeOptions,
+    UpgradeOutcome, UpgradeStage, WasmModule,
+};
+use ic_call_retry::{
+    call_idempotent_method_with_retry, call_nonidempotent_method_with_retry, ErrorCause, RetryError,
+};
+use ic_cdk::call::CallErrorExt;
+use ic_cdk::management_canister::{
+    DeleteCanisterSnapshotArgs, ListCanisterSnapshotsArgs, ListCanisterSnapshotsResult, SnapshotId,
+    TakeCanisterSnapshotArgs, TakeCanisterSnapshotResult,
+};
+use ic_management_canister_types::LoadCanisterSnapshotArgs;
+use std::future::Future;
+
+/// Describes the stage of the rollback during which an error occurred.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RollbackStage {
+    Stopping,
+    LoadingSnapshot,
+    Starting,
+}
+
+/// What happened to the target after a failed upgrade.
+#[derive(Debug, Clone)]
+pub enum RollbackOutcome {
+    /// No rollback was attempted. Either the upgrade failed before the target was stopped, or
+    /// a concurrent change was detected, which a rollback would have overwritten. In the latter
+    /// case, the target is left stopped.
+    NotAttempted,
+    /// The upgrade failed after the target was stopped, but before it was changed (possibly
+    /// while taking the snapshot), so no snapshot was loaded; the target was just restarted.
+    Restarted,
+    /// The snapshot was loaded and the target restarted.
+    RolledBack,
+    /// The rollback failed. The snapshot is left in place, so the rollback can be retried
+    /// manually.
+    Failed {
+        stage: RollbackStage,
+        error: RetryError,
+    },
+}
+
+/// Errors returned by `upgrade_canister_with_rollback`.
+#[derive(Debug, Clone)]
+pub struct RollbackUpgradeError {
+    /// Why the upgrade failed.
+    pub upgrade_error: UpgradeError,
+    /// Whether the target was rolled back to its state before the upgrade.
+    pub rollback: RollbackOutcome,
+    /// The snapshot taken before the upgrade, if it wasn't deleted: because the rollback failed
+    /// (so that it can be retried manually), because of a concurrent change, or because deleting
+    /// it failed. If taking the snapshot had an unknown outcome, this is the snapshot that showed
+    /// up nonetheless, if any. It counts against the target's snapshot quota until it's deleted.
+    pub leftover_snapshot: Option<SnapshotId>,
+}
+
+/// The result of a successful call to `upgrade_canister_with_rollback`.
+#[derive(Debug, Clone)]
+pub struct RollbackUpgradeSuccess {
+    pub outcome: UpgradeOutcome,
+    /// The snapshot taken before the upgrade, if deleting it failed. It counts against the
+    /// target's snapshot quota until it's deleted.
+    pub leftover_snapshot: Option<SnapshotId>,
+}
+
+/// Safely upgrade a canister, rolling it back to a snapshot if the upgrade fails.
+///
+/// Works like `upgrade_canister`, but takes a canister snapshot (`take_canister_snapshot`) after
+/// stopping the target, and runs the `health_check` after the target has been started again
+/// (following the `health_check` in `options`, if any). If the installation, restart, or health
+/// checks fail, the snapshot is loaded (`load_canister_snapshot`) and the target restarted. If
+/// the upgrade fails after the snapshot was taken but before the target was changed (e.g., because
+/// the `precondition` doesn't hold), the target is just restarted. The same happens if taking the
+/// snapshot fails; if the outcome of taking it is unknown, any snapshot that it may have created
+/// is reported as the `leftover_snapshot`. The snapshot is deleted after a successful upgrade,
+/// rollback or restart; if that fails, its ID is reported as the `leftover_snapshot`.
+///
+/// All steps use bounded-wait calls with retries, except for the unbounded-wait calls to the
+/// `proxy`, if any (see `upgrade_canister`). The upgrade steps are retried until
+/// `stop_trying` returns true, and the rollback and clean-up steps until `rollback_stop_trying`
+/// returns true. The latter should leave enough time for the rollback even if the upgrade ran out
+/// of time.
+///
+/// If the `skip_if_up_to_date` option is set and the target is up to date, nothing is done: in
+/// particular, no snapshot is taken and the `health_check` isn't run.
+///
+/// Note that snapshots count against the target's snapshot quota. If the target already has the
+/// maximum number of snapshots, the upgrade fails at the `TakingSnapshot` stage.
+///
+/// # Arguments
+/// * `target_id` - The canister to upgrade
+/// * `wasm_module` - The new module
+/// * `arg` - The Candid-encoded argument to the `post_upgrade` hook
+/// * `options` - Upgrade flags, such as `skip_pre_upgrade`
+/// * `health_check` - Checks the target once it has been upgraded and restarted, returning an
+///   error message if the target is unhealthy
+/// * `stop_trying` - A function that determines when to stop retrying the upgrade calls
+/// * `rollback_stop_trying` - A function that determines when to stop retrying the rollback calls
+///
+/// # Returns
+/// * `Ok(RollbackUpgradeSuccess)` with `UpgradeOutcome::Upgraded` if we can confirm a successful
+///   upgrade that passed the health check, or with `UpgradeOutcome::AlreadyUpToDate` if the
+///   upgrade was skipped.
+/// * `Err(RollbackUpgradeError)` describing the stage at which the upgrade failed, and the
+///   outcome of the rollback.
+pub async fn upgrade_canister_with_rollback<P, R, H, F>(
+    target_id: CanisterId,
+    wasm_module: WasmModule,
+    arg: Vec<u8>,
+    options: UpgradeOptions,
+    health_check: H,
+    stop_trying: &mut P,
+    rollback_stop_trying: &mut R,
+) -> Result<RollbackUpgradeSuccess, RollbackUpgradeError>
+where
+    P: FnMut() -> bool,
+    R: FnMut() -> bool,
+    H: FnOnce() -> F,
+    F: Future<Output = Result<(), String>>,
+{
+    let not_attempted = |upgrade_error: UpgradeError| RollbackUpgradeError {
+        upgrade_error,
+        rollback: RollbackOutcome::NotAttempted,
+        leftover_snapshot: None,
+    };
+    let _lock = lock_target(target_id, options.wait_for_lock, stop_trying)
+        .await
+        .map_err(not_attempted)?;
+    let target = Target::new(target_id, &options);
+
+    pre_flight(
+        target,
+        &wasm_module,
+        &arg,
+        &options,
+        &Progress::silent(),
+        stop_trying,
+    )
+    .await
+    .map_err(not_attempted)?;
+
+    if let Some(check) = &options.skip_if_up_to_date {
+        if is_up_to_date(target, check, &wasm_module, &arg, stop_trying)
+            .await
+            .map_err(not_attempted)?
+        {
+            return Ok(RollbackUpgradeSuccess {
+                outcome: UpgradeOutcome::AlreadyUpToDate,
+                leftover_snapshot: None,
+            });
+        }
+    }
+
+    let wasm_module = prepare_module(target, wasm_module, &arg, stop_trying)
+        .await
+        .map_err(|error| {
+            not_attempted(UpgradeError {
+                stage: UpgradeStage::UploadingChunks,
+                reason: UpgradeErrorReason::ChunkUploadFailed(error),
+            })
+        })?;
+
+    stop_or_recover(target, options.restart_on_stop_failure, stop_trying)
+        .await
+        .map_err(not_attempted)?;
+
+    let snapshot = match bounded_wait_list_snapshots(target, stop_trying).await {
+        Ok(existing) => bounded_wait_take_snapshot(target, &existing, stop_trying)
+            .await
+            .map_err(|error| (error, Some(existing))),
+        Err(error) => Err((error, None)),
+    };
+    let snapshot_id = match snapshot {
+        Ok(snapshot_id) => snapshot_id,
+        // Nothing has changed yet, except that the target is stopped
+        Err((error, existing)) => {
+            let rollback = match bounded_wait_start(target, rollback_stop_trying).await {
+                Ok(()) => RollbackOutcome::Restarted,
+                Err(error) => RollbackOutcome::Failed {
+                    stage: RollbackStage::Starting,
+                    error,
+                },
+            };
+            // An attempt with an unknown outcome may still have created a snapshot
+            let leftover_snapshot = match (&error, existing) {
+                (RetryError::StatusUnknown(_), Some(existing)) => {
+                    find_new_snapshot(target, &existing, rollback_stop_trying)
+                        .await
+                        .ok()
+                        .flatten()
+                }
+                _ => None,
+            };
+            return Err(RollbackUpgradeError {
+                upgrade_error: add_stage(UpgradeStage::TakingSnapshot)(error),
+                rollback,
+                leftover_snapshot,
+            });
+        }
+    };
+
+    let upgrade_result = upgrade_and_check(
+        target,
+        &wasm_module,
+        &arg,
+        &options,
+        health_check,
+        stop_trying,
+    )
+    .await;
+
+    let upgrade_error = match upgrade_result {
+        Ok(()) => {
+            return Ok(RollbackUpgradeSuccess {
+                outcome: UpgradeOutcome::Upgraded,
+                leftover_snapshot: delete_snapshot(target, snapshot_id, rollback_stop_trying).await,
+            });
+        }
+        Err(upgrade_error) => upgrade_error,
+    };
+
+    let recovery = match (&upgrade_error.stage, &upgrade_error.reason) {
+        // Keep the snapshot and the stopped target for whoever resolves the concurrent change
+        (_, UpgradeErrorReason::ConcurrentChangeDetected(_)) => {
+            return Err(RollbackUpgradeError {
+                upgrade_error,
+                rollback: RollbackOutcome::NotAttempted,
+                leftover_snapshot: Some(snapshot_id),
+            })
+        }
+        (UpgradeStage::Installing, _)
+        | (UpgradeStage::Starting, _)
+        | (UpgradeStage::Verifying, _) => {
+            roll_back(target, &snapshot_id, rollback_stop_trying).await
+        }
+        // Nothing has changed yet, except that the target is stopped
+        (_, _) => bounded_wait_start(target, rollback_stop_trying)
+            .await
+            .map(|()| RollbackOutcome::Restarted)
+            .map_err(|e| (RollbackStage::Starting, e)),
+    };
+
+    Err(match recovery {
+        Ok(rollback) => RollbackUpgradeError {
+            upgrade_error,
+            rollback,
+            leftover_snapshot: delete_snapshot(target, snapshot_id, rollback_stop_trying).await,
+        },
+        Err((stage, error)) => RollbackUpgradeError {
+            upgrade_error,
+            rollback: RollbackOutcome::Failed { stage, error },
+            leftover_snapshot: Some(snapshot_id),
+        },
+    })
+}
+
+/// Deletes the snapshot, returning it if that fails.
+async fn delete_snapshot<P>(
+    target: Target<'_>,
+    snapshot_id: SnapshotId,
+    stop_trying: &mut P,
+) -> Option<SnapshotId>
+where
+    P: FnMut() -> bool,
+{
+    bounded_wait_delete_snapshot(target, &snapshot_id, stop_trying)
+        .await
+        .err()
+        .map(|_| snapshot_id)
+}
+
+/// Installs the module into the stopped target, restarts it, and runs the health checks.
+async fn upgrade_and_check<P, H, F>(
+    target: Target<'_>,
+    wasm_module: &WasmModule,
+    arg: &[u8],
+    options: &UpgradeOptions,
+    health_check: H,
+    stop_trying: &mut P,
+) -> Result<(), UpgradeError>
+where
+    P: FnMut() -> bool,
+    H: FnOnce() -> F,
+    F: Future<Output = Result<(), String>>,
+{
+    install_stopped(
+        target,
+        options.install_mode(),
+        wasm_module,
+        arg,
+        options.precondition.as_ref(),
+        &Progress::silent(),
+        stop_trying,
+    )
+    .await?;
+
+    bounded_wait_start(target, stop_trying)
+        .await
+        .map_err(add_stage(UpgradeStage::Starting))?;
+
+    if let Some(check) = &options.health_check {
+        verify(target, check, stop_trying).await?;
+    }
+
+    health_check().await.map_err(|message| UpgradeError {
+        stage: UpgradeStage::Verifying,
+        reason: UpgradeErrorReason::HealthCheckFailed(message),
+    })
+}
+
+/// Stops the target, loads the snapshot, and starts the target again.
+async fn roll_back<R>(
+    target: Target<'_>,
+    snapshot_id: &SnapshotId,
+    stop_trying: &mut R,
+) -> Result<RollbackOutcome, (RollbackStage, RetryError)>
+where
+    R: FnMut() -> bool,
+{
+    bounded_wait_stop(target, stop_trying)
+        .await
+        .map_err(|e| (RollbackStage::Stopping, e))?;
+
+    // Loading the same snapshot multiple times has the same effect as loading it once, so we
+    // can retry freely.
+    let args = LoadCanisterSnapshotArgs {
+        canister_id: target.id,
+        snapshot_id: snapshot_id.clone(),
+        sender_canister_version: target.sender_canister_version(),
+    };
+    let _: () = call_idempotent_method_with_retry(
+        target.management_call("load_canister_snapshot", &args),
+        stop_trying,
+    )
+    .await
+    .map_err(|e| (RollbackStage::LoadingSnapshot, e))?
+    .candid()
+    .unwrap();
+
+    bounded_wait_start(target, stop_trying)
+        .await
+        .map(|()| RollbackOutcome::RolledBack)
+        .map_err(|e| (RollbackStage::Starting, e))
+}
+
+/// Take a snapshot of a (stopped) canister.
+///
+/// Taking a snapshot isn't idempotent, since every snapshot counts against the canister's
+/// snapshot quota. If the outcome of an attempt is unknown, we compare the canister's snapshots
+/// to the `existing` ones, listed before the first attempt, and only retry if no new snapshot
+/// shows up.
+async fn bounded_wait_take_snapshot<P>(
+    target: Target<'_>,
+    existing: &ListCanisterSnapshotsResult,
+    stop_trying: &mut P,
+) -> Result<SnapshotId, RetryError>
+where
+    P: FnMut() -> bool,
+{
+    let args = TakeCanisterSnapshotArgs {
+        canister_id: target.id,
+        replace_snapshot: None,
+    };
+
+    loop {
+        let result = call_nonidempotent_method_with_retry(
+            target.management_call("take_canister_snapshot", &args),
+            stop_trying,
+        )
+        .await;
+        match result {
+            Ok(response) => {
+                let snapshot: TakeCanisterSnapshotResult = response.candid().unwrap();
+                return Ok(snapshot.id);
+            }
+            Err(RetryError::StatusUnknown(ErrorCause::CallFailed(rejection)))
+                if !rejection.is_clean_reject() =>
+            {
+                if let Some(new) = find_new_snapshot(target, existing, stop_trying).await? {
+                    return Ok(new);
+                }
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// Finds a snapshot of the target that isn't among the `existing` ones.
+async fn find_new_snapshot<P>(
+    target: Target<'_>,
+    existing: &ListCanisterSnapshotsResult,
+    stop_trying: &mut P,
+) -> Result<Option<SnapshotId>, RetryError>
+where
+    P: FnMut() -> bool,
+{
+    let current = bounded_wait_list_snapshots(target, stop_trying).await?;
+    Ok(current
+        .into_iter()
+        .find(|snapshot| !existing.iter().any(|old| old.id == snapshot.id))
+        .map(|snapshot| snapshot.id))
+}
+
+async fn bounded_wait_list_snapshots<P>(
+    target: Target<'_>,
+    stop_trying: &mut P,
+) -> Result<ListCanisterSnapshotsResult, RetryError>
+where
+    P: FnMut() -> bool,
+{
+    let args = ListCanisterSnapshotsArgs {
+        canister_id: target.id,
+    };
+    Ok(call_idempotent_method_with_retry(
+        target.management_call("list_canister_snapshots", &args),
+        stop_trying,
+    )
+    .await?
+    .candid()
+    .unwrap())
+}
+
+async fn bounded_wait_delete_snapshot<P>(
+    target: Target<'_>,
+    snapshot_id: &SnapshotId,
+    stop_trying: &mut P,
+) -> Result<(), RetryError>
+where
+    P: FnMut() -> bool,
+{
+    let args = DeleteCanisterSnapshotArgs {
+        canister_id: target.id,
+        snapshot_id: snapshot_id.clone(),
+    };
+    let _: () = call_idempotent_method_with_retry(
+        target.management_call("delete_canister_snapshot", &args),
+        stop_trying,
+    )
+    .await?
+    .candid()
+    .unwrap();
+    Ok(())
+}
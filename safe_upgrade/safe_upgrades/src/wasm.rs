@@ -0,0 +1,288 @@
+use crate::WasmModule;
+use candid::CandidType;
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io::Read;
+
+const WASM_MAGIC: &[u8] = b"\0asm";
+const WASM_VERSION: &[u8] = &[1, 0, 0, 0];
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const CUSTOM_SECTION_ID: u8 = 0;
+const EXPORT_SECTION_ID: u8 = 7;
+const FUNCTION_EXPORT_KIND: u8 = 0;
+/// The order in which the non-custom sections must appear; the data count section (12) goes
+/// between the element (9) and the code (10) sections.
+const SECTION_ORDER: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 12, 10, 11];
+/// The maximum size of a decompressed module that the IC accepts.
+pub const MAX_DECOMPRESSED_MODULE_SIZE: usize = 100 * 1024 * 1024;
+
+/// What a WASM module exports to the IC, as found by `inspect_module`.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModuleInfo {
+    /// Whether the module is gzip-compressed.
+    pub compressed: bool,
+    /// The size of the (decompressed) module in bytes.
+    pub size: usize,
+    /// The exported functions whose names start with `canister_`, e.g., `canister_update foo` or
+    /// `canister_post_upgrade`, in module order.
+    pub entry_points: Vec<String>,
+    /// The `icp:public` metadata sections, by name (without the `icp:public ` prefix).
+    pub public_metadata: BTreeMap<String, Vec<u8>>,
+    /// The `icp:private` metadata sections, by name (without the `icp:private ` prefix).
+    pub private_metadata: BTreeMap<String, Vec<u8>>,
+}
+
+impl ModuleInfo {
+    /// The names of the update methods.
+    pub fn update_methods(&self) -> impl Iterator<Item = &str> {
+        self.methods("canister_update ")
+    }
+
+    /// The names of the query methods, including composite queries.
+    pub fn query_methods(&self) -> impl Iterator<Item = &str> {
+        self.methods("canister_query ")
+            .chain(self.methods("canister_composite_query "))
+    }
+
+    pub fn has_pre_upgrade(&self) -> bool {
+        self.has_entry_point("canister_pre_upgrade")
+    }
+
+    pub fn has_post_upgrade(&self) -> bool {
+        self.has_entry_point("canister_post_upgrade")
+    }
+
+    fn has_entry_point(&self, name: &str) -> bool {
+        self.entry_points
+            .iter()
+            .any(|entry_point| entry_point == name)
+    }
+
+    fn methods<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = &'a str> {
+        self.entry_points
+            .iter()
+            .filter_map(move |entry_point| entry_point.strip_prefix(prefix))
+    }
+}
+
+/// Requirements on the new module, checked before the target is touched when set as the
+/// `module_checks` upgrade option.
+///
+/// With the defaults, the module must be a valid WASM module exporting at least one update
+/// method; a module without any is unlikely to be a canister that anyone meant to deploy.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModuleChecks {
+    /// Update methods that the module must export, e.g., the ones that clients rely on.
+    pub required_update_methods: Vec<String>,
+    /// Require the module to export a `canister_post_upgrade` hook, e.g., because the state must
+    /// be restored from stable memory after an upgrade.
+    pub require_post_upgrade: bool,
+    /// Metadata sections (public or private) that the module must have, e.g., `candid:service`.
+    pub required_metadata: Vec<String>,
+}
+
+/// Why the new module failed the `module_checks`.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ModuleCheckError {
+    /// The module is a `WasmModule::ChunkedModule`, whose bytes aren't available for inspection.
+    ModuleNotAvailable,
+    /// The module isn't a valid (possibly gzip-compressed) WASM module.
+    InvalidModule(String),
+    /// The module doesn't export any update methods.
+    NoUpdateMethods,
+    MissingUpdateMethod(String),
+    MissingPostUpgrade,
+    MissingMetadata(String),
+}
+
+/// Parses a WASM module, which may be gzip-compressed, and reports what it exports to the IC.
+///
+/// This checks the structure of the module (the header, and the framing and order of the
+/// sections) and parses its exports and custom sections, but doesn't validate the code; the IC
+/// still does that when installing the module.
+pub fn inspect_module(module: &[u8]) -> Result<ModuleInfo, String> {
+    let compressed = module.starts_with(GZIP_MAGIC);
+    let module = decompress(module)?;
+    let mut info = ModuleInfo {
+        compressed,
+        size: module.len(),
+        ..Default::default()
+    };
+    for (id, contents) in sections(&module)? {
+        match id {
+            CUSTOM_SECTION_ID => {
+                let mut pos = 0;
+                let name = read_name(contents, &mut pos)?;
+                let data = contents[pos..].to_vec();
+                if let Some(name) = name.strip_prefix("icp:public ") {
+                    info.public_metadata.insert(name.to_string(), data);
+                } else if let Some(name) = name.strip_prefix("icp:private ") {
+                    info.private_metadata.insert(name.to_string(), data);
+                }
+            }
+            EXPORT_SECTION_ID => {
+                let mut pos = 0;
+                for _ in 0..read_u32(contents, &mut pos)? {
+                    let name = read_name(contents, &mut pos)?;
+                    let kind = *contents
+                        .get(pos)
+                        .ok_or_else(|| "export section is truncated".to_string())?;
+                    pos += 1;
+                    read_u32(contents, &mut pos)?;
+                    if kind == FUNCTION_EXPORT_KIND && name.starts_with("canister_") {
+                        info.entry_points.push(name.to_string());
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(info)
+}
+
+/// Inspects the module and checks it against the requirements.
+pub fn check_module(
+    wasm_module: &WasmModule,
+    checks: &ModuleChecks,
+) -> Result<ModuleInfo, ModuleCheckError> {
+    let bytes = match wasm_module {
+        WasmModule::Bytes(bytes) | WasmModule::Auto { bytes, .. } => bytes,
+        WasmModule::ChunkedModule(_) => return Err(ModuleCheckError::ModuleNotAvailable),
+    };
+    let info = inspect_module(bytes).map_err(ModuleCheckError::InvalidModule)?;
+    if info.update_methods().next().is_none() {
+        return Err(ModuleCheckError::NoUpdateMethods);
+    }
+    if let Some(missing) = checks
+        .required_update_methods
+        .iter()
+        .find(|method| !info.update_methods().any(|name| name == method.as_str()))
+    {
+        return Err(ModuleCheckError::MissingUpdateMethod(missing.clone()));
+    }
+    if checks.require_post_upgrade && !info.has_post_upgrade() {
+        return Err(ModuleCheckError::MissingPostUpgrade);
+    }
+    if let Some(missing) = checks.required_metadata.iter().find(|name| {
+        !info.public_metadata.contains_key(*name) && !info.private_metadata.contains_key(*name)
+    }) {
+        return Err(ModuleCheckError::MissingMetadata(missing.clone()));
+    }
+    Ok(info)
+}
+
+/// Decompresses a gzip-compressed module; other modules are returned as they are.
+pub(crate) fn decompress(module: &[u8]) -> Result<Cow<'_, [u8]>, String> {
+    if !module.starts_with(GZIP_MAGIC) {
+        return Ok(Cow::Borrowed(module));
+    }
+    let mut decompressed = vec![];
+    GzDecoder::new(module)
+        .take(MAX_DECOMPRESSED_MODULE_SIZE as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| format!("gzip decompression failed: {}", e))?;
+    if decompressed.len() > MAX_DECOMPRESSED_MODULE_SIZE {
+        return Err(format!(
+            "the decompressed module exceeds {} bytes",
+            MAX_DECOMPRESSED_MODULE_SIZE
+        ));
+    }
+    Ok(Cow::Owned(decompressed))
+}
+
+/// The sections of an uncompressed WASM module, as `(id, contents)` pairs in module order.
+pub(crate) fn sections(module: &[u8]) -> Result<Vec<(u8, &[u8])>, String> {
+    if !module.starts_with(WASM_MAGIC) || module.get(4..8) != Some(WASM_VERSION) {
+        return Err("not a WASM module (bad magic number or version)".to_string());
+    }
+    let mut pos = 8;
+    let mut sections = vec![];
+    let mut last_rank = None;
+    while pos < module.len() {
+        let id = module[pos];
+        pos += 1;
+        if id != CUSTOM_SECTION_ID {
+            let rank = SECTION_ORDER
+                .iter()
+                .position(|&known| known == id)
+                .ok_or_else(|| format!("unknown section {} at offset {}", id, pos - 1))?;
+            if last_rank.is_some_and(|last| rank <= last) {
+                return Err(format!(
+                    "section {} at offset {} is out of order",
+                    id,
+                    pos - 1
+                ));
+            }
+            last_rank = Some(rank);
+        }
+        let size = read_u32(module, &mut pos)? as usize;
+        let contents = pos
+            .checked_add(size)
+            .and_then(|end| module.get(pos..end))
+            .ok_or_else(|| format!("section {} at offset {} is truncated", id, pos))?;
+        sections.push((id, contents));
+        pos += size;
+    }
+    Ok(sections)
+}
+
+/// The contents of the custom section with the given name, if the uncompressed module has one.
+pub(crate) fn custom_section<'a>(module: &'a [u8], name: &str) -> Result<Option<&'a [u8]>, String> {
+    for (id, contents) in sections(module)? {
+        if id != CUSTOM_SECTION_ID {
+            continue;
+        }
+        let mut pos = 0;
+        let section_name = read_name(contents, &mut pos)?;
+        if section_name == name {
+            return Ok(Some(&contents[pos..]));
+        }
+    }
+    Ok(None)
+}
+
+/// The module's Candid interface, from its public or private `candid:service` metadata section.
+/// The module may be gzip-compressed.
+pub(crate) fn candid_service(module: &[u8]) -> Result<Option<String>, String> {
+    let module = decompress(module)?;
+    let section = match custom_section(&module, "icp:public candid:service")? {
+        Some(section) => Some(section),
+        None => custom_section(&module, "icp:private candid:service")?,
+    };
+    section
+        .map(|bytes| {
+            String::from_utf8(bytes.to_vec())
+                .map_err(|_| "the candid:service section isn't valid UTF-8".to_string())
+        })
+        .transpose()
+}
+
+/// Reads an unsigned LEB128-encoded 32-bit integer.
+fn read_u32(bytes: &[u8], pos: &mut usize) -> Result<u32, String> {
+    let mut result: u64 = 0;
+    for shift in (0..35).step_by(7) {
+        let byte = *bytes
+            .get(*pos)
+            .ok_or_else(|| format!("integer at offset {} is truncated", pos))?;
+        *pos += 1;
+        result |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return u32::try_from(result)
+                .map_err(|_| format!("integer ending at offset {} is too large", pos));
+        }
+    }
+    Err(format!("integer ending at offset {} is too long", pos))
+}
+
+/// Reads a length-prefixed UTF-8 name.
+fn read_name<'a>(bytes: &'a [u8], pos: &mut usize) -> Result<&'a str, String> {
+    let len = read_u32(bytes, pos)? as usize;
+    let name = pos
+        .checked_add(len)
+        .and_then(|end| bytes.get(*pos..end))
+        .ok_or_else(|| format!("name at offset {} is truncated", pos))?;
+    *pos += len;
+    std::str::from_utf8(name).map_err(|_| format!("name ending at offset {} isn't UTF-8", pos))
+}
@@ -1,7 +1,10 @@
-use candid::{decode_one, encode_args, encode_one, Principal};
+use candid::{decode_args, decode_one, encode_args, encode_one, Principal};
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use once_cell::sync::Lazy;
 use pocket_ic::PocketIc;
 use pocket_ic_utils::{build_wasm, get_workspace_root};
+use std::io::Write;
 use std::path::PathBuf;
 
 // --- Constants ---
@@ -92,15 +95,35 @@ fn try_upgrading_target(
     upgrader_canister_id: Principal,
     target_canister_id: Principal,
     deadline: u64,
+) -> Result<(), String> {
+    try_upgrading_target_with(pic, "try_upgrading_target", upgrader_canister_id, target_canister_id, deadline)
+}
+
+fn try_upgrading_target_with(
+    pic: &PocketIc,
+    method: &str,
+    upgrader_canister_id: Principal,
+    target_canister_id: Principal,
+    deadline: u64,
 ) -> Result<(), String> {
     let target_v2_wasm_bytes =
         std::fs::read(&*TARGET_V2_WASM_PATH).expect("Failed to read Wasm file");
+    try_upgrading_target_to(pic, method, upgrader_canister_id, target_canister_id, target_v2_wasm_bytes, deadline)
+}
 
+fn try_upgrading_target_to(
+    pic: &PocketIc,
+    method: &str,
+    upgrader_canister_id: Principal,
+    target_canister_id: Principal,
+    target_v2_wasm_bytes: Vec<u8>,
+    deadline: u64,
+) -> Result<(), String> {
     let message_id = pic
         .submit_call(
             upgrader_canister_id,
             Principal::anonymous(),
-            "try_upgrading_target",
+            method,
             encode_args((target_canister_id, target_v2_wasm_bytes, deadline))
                 .expect("Couldn't encode args"),
         )
@@ -148,6 +171,32 @@ fn version_check(
 
 }
 
+/// Appends a `candid:service` metadata section with the given interface to the module, as
+/// `ic-wasm metadata` would.
+fn with_candid_service(wasm: &[u8], service: &str) -> Vec<u8> {
+    fn leb128(mut n: usize, out: &mut Vec<u8>) {
+        loop {
+            let byte = (n & 0x7f) as u8;
+            n >>= 7;
+            if n == 0 {
+                out.push(byte);
+                return;
+            }
+            out.push(byte | 0x80);
+        }
+    }
+    let name = b"icp:public candid:service";
+    let mut contents = vec![];
+    leb128(name.len(), &mut contents);
+    contents.extend_from_slice(name);
+    contents.extend_from_slice(service.as_bytes());
+    let mut module = wasm.to_vec();
+    module.push(0);
+    leb128(contents.len(), &mut module);
+    module.extend(contents);
+    module
+}
+
 #[test]
 fn upgrade_works_when_no_failures() -> Result<(), String> {
     let pic = &PocketIc::new();
@@ -203,6 +252,1263 @@ fn upgrade_works_with_probability_policy() -> Result<(), String> {
     Ok(())
 }
 
+#[test]
+fn chunked_upgrade_works_with_allow_every_other_policy() -> Result<(), String> {
+    let pic = &PocketIc::new();
+    let (upgrader_canister_id, target_canister_id) = install_canisters(pic);
+    set_policy(pic, upgrader_canister_id, "AllowEveryOther");
+
+    let curr_time = pic.get_time().as_nanos_since_unix_epoch();
+    let deadline = curr_time + 100; // 100 rounds, to allow for the uploads and some failures
+    let res = try_upgrading_target_with(
+        pic,
+        "try_upgrading_target_chunked",
+        upgrader_canister_id,
+        target_canister_id,
+        deadline,
+    );
+    assert!(res.is_ok(), "Chunked upgrade failed: {:?}", res);
+
+    version_check(pic, target_canister_id, 2, 2)?;
+
+    // Uploading the same module again is a no-op, and the chunked upgrade still goes through
+    set_policy(pic, upgrader_canister_id, "AllowAll");
+    let curr_time = pic.get_time().as_nanos_since_unix_epoch();
+    let res = try_upgrading_target_with(
+        pic,
+        "try_upgrading_target_chunked",
+        upgrader_canister_id,
+        target_canister_id,
+        curr_time + 100,
+    );
+    assert!(res.is_ok(), "Repeated chunked upgrade failed: {:?}", res);
+
+    version_check(pic, target_canister_id, 2, 3)?;
+
+    Ok(())
+}
+
+#[test]
+fn skip_pre_upgrade_recovers_a_trapping_canister() -> Result<(), String> {
+    let pic = &PocketIc::new();
+    let (upgrader_canister_id, target_canister_id) = install_canisters(pic);
+    set_policy(pic, upgrader_canister_id, "AllowEveryOther");
+
+    pic.update_call(
+        target_canister_id,
+        Principal::anonymous(),
+        "set_trap_in_pre_upgrade",
+        encode_one(true).expect("Couldn't encode args"),
+    )
+    .expect("Failed to make pre_upgrade trap");
+
+    // A plain upgrade fails, since the pre_upgrade hook traps
+    let curr_time = pic.get_time().as_nanos_since_unix_epoch();
+    let res = try_upgrading_target(pic, upgrader_canister_id, target_canister_id, curr_time + 50);
+    assert!(res.is_err(), "Upgrade should fail when pre_upgrade traps");
+    pic.start_canister(target_canister_id, None).expect("Failed to start target canister");
+    version_check(pic, target_canister_id, 1, 1)?;
+
+    // Skipping the pre_upgrade hook works
+    let curr_time = pic.get_time().as_nanos_since_unix_epoch();
+    let res = try_upgrading_target_with(
+        pic,
+        "try_upgrading_target_skipping_pre_upgrade",
+        upgrader_canister_id,
+        target_canister_id,
+        curr_time + 50,
+    );
+    assert!(res.is_ok(), "Upgrade skipping pre_upgrade failed: {:?}", res);
+    version_check(pic, target_canister_id, 2, 2)?;
+
+    Ok(())
+}
+
+fn try_upgrading_target_with_rollback(
+    pic: &PocketIc,
+    upgrader_canister_id: Principal,
+    target_canister_id: Principal,
+    expected_version: u32,
+    deadline: u64,
+) -> Result<(), String> {
+    try_upgrading_target_expecting_version(
+        pic,
+        "try_upgrading_target_with_rollback",
+        upgrader_canister_id,
+        target_canister_id,
+        expected_version,
+        deadline,
+    )
+}
+
+fn try_upgrading_target_expecting_version(
+    pic: &PocketIc,
+    method: &str,
+    upgrader_canister_id: Principal,
+    target_canister_id: Principal,
+    expected_version: u32,
+    deadline: u64,
+) -> Result<(), String> {
+    let target_v2_wasm_bytes =
+        std::fs::read(&*TARGET_V2_WASM_PATH).expect("Failed to read Wasm file");
+    let message_id = pic
+        .submit_call(
+            upgrader_canister_id,
+            Principal::anonymous(),
+            method,
+            encode_args((target_canister_id, target_v2_wasm_bytes, expected_version, deadline))
+                .expect("Couldn't encode args"),
+        )
+        .expect("Failed to submit the upgrade call");
+
+    while pic.get_time().as_nanos_since_unix_epoch() < deadline && pic.ingress_status(message_id.clone()).is_none() {
+        pic.tick();
+    }
+
+    let response = pic.await_call(message_id).expect("Failed to await call");
+    decode_one(&response).expect("Failed to decode response")
+}
+
+#[test]
+fn health_check_verifies_the_upgraded_target() -> Result<(), String> {
+    let pic = &PocketIc::new();
+
+    let (upgrader_canister_id, target_canister_id) = install_canisters(pic);
+    set_policy(pic, upgrader_canister_id, "AllowEveryOther");
+    let curr_time = pic.get_time().as_nanos_since_unix_epoch();
+    let res = try_upgrading_target_expecting_version(
+        pic,
+        "try_upgrading_target_with_health_check",
+        upgrader_canister_id,
+        target_canister_id,
+        2,
+        curr_time + 100,
+    );
+    assert!(res.is_ok(), "Upgrade failed: {:?}", res);
+    version_check(pic, target_canister_id, 2, 2)?;
+
+    let (upgrader_canister_id, target_canister_id) = install_canisters(pic);
+    set_policy(pic, upgrader_canister_id, "AllowEveryOther");
+    let curr_time = pic.get_time().as_nanos_since_unix_epoch();
+    let res = try_upgrading_target_expecting_version(
+        pic,
+        "try_upgrading_target_with_health_check",
+        upgrader_canister_id,
+        target_canister_id,
+        3,
+        curr_time + 100,
+    );
+    let err = res.expect_err("Upgrade should fail the health check");
+    assert!(err.contains("Verifying") && err.contains("HealthCheckFailed"), "Unexpected error: {}", err);
+    // Without a rollback, the target keeps running the new version
+    version_check(pic, target_canister_id, 2, 2)?;
+
+    Ok(())
+}
+
+#[test]
+fn upgrade_with_rollback_rolls_back_on_failed_health_check() -> Result<(), String> {
+    let pic = &PocketIc::new();
+    let (upgrader_canister_id, target_canister_id) = install_canisters(pic);
+    set_policy(pic, upgrader_canister_id, "AllowEveryOther");
+
+    // The health check expects a version that v2 doesn't report, so the upgrade is rolled back
+    let curr_time = pic.get_time().as_nanos_since_unix_epoch();
+    let res = try_upgrading_target_with_rollback(pic, upgrader_canister_id, target_canister_id, 3, curr_time + 100);
+    let err = res.expect_err("Upgrade should fail the health check");
+    assert!(err.contains("Verifying") && err.contains("RolledBack"), "Unexpected error: {}", err);
+    // The upgrade shows up in the history, but the target runs v1 again
+    version_check(pic, target_canister_id, 1, 2)?;
+
+    let curr_time = pic.get_time().as_nanos_since_unix_epoch();
+    let res = try_upgrading_target_with_rollback(pic, upgrader_canister_id, target_canister_id, 2, curr_time + 100);
+    assert!(res.is_ok(), "Upgrade failed: {:?}", res);
+    version_check(pic, target_canister_id, 2, 3)?;
+
+    Ok(())
+}
+
+#[test]
+fn upgrade_with_rollback_restarts_target_when_precondition_fails() -> Result<(), String> {
+    let pic = &PocketIc::new();
+    let (upgrader_canister_id, target_canister_id) = install_canisters(pic);
+    set_policy(pic, upgrader_canister_id, "AllowEveryOther");
+    let target_v2_wasm_bytes =
+        std::fs::read(&*TARGET_V2_WASM_PATH).expect("Failed to read Wasm file");
+
+    // The precondition fails only after the target is stopped and the snapshot taken
+    let deadline = pic.get_time().as_nanos_since_unix_epoch() + 100;
+    let response = pic
+        .update_call(
+            upgrader_canister_id,
+            Principal::anonymous(),
+            "try_upgrading_target_with_rollback_and_precondition",
+            encode_args((target_canister_id, target_v2_wasm_bytes, 1000_u64, deadline))
+                .expect("Couldn't encode args"),
+        )
+        .expect("Failed to call try_upgrading_target_with_rollback_and_precondition");
+    let res: Result<(), String> = decode_one(&response).expect("Failed to decode response");
+    let err = res.expect_err("Upgrade should fail the precondition");
+    assert!(
+        err.contains("PreconditionFailed") && err.contains("Restarted") && err.contains("leftover_snapshot: None"),
+        "Unexpected error: {}",
+        err
+    );
+    // The target is running again, and wasn't changed
+    version_check(pic, target_canister_id, 1, 1)?;
+
+    Ok(())
+}
+
+#[test]
+fn upgrade_with_rollback_restarts_target_when_snapshot_fails() -> Result<(), String> {
+    let pic = &PocketIc::new();
+    let (upgrader_canister_id, target_canister_id) = install_canisters(pic);
+    set_policy(pic, upgrader_canister_id, "DenySnapshots");
+
+    let curr_time = pic.get_time().as_nanos_since_unix_epoch();
+    let res = try_upgrading_target_with_rollback(pic, upgrader_canister_id, target_canister_id, 2, curr_time + 100);
+    let err = res.expect_err("Upgrade should fail to take the snapshot");
+    assert!(
+        err.contains("TakingSnapshot") && err.contains("Restarted") && err.contains("leftover_snapshot: None"),
+        "Unexpected error: {}",
+        err
+    );
+    // The target is running again, and wasn't changed
+    version_check(pic, target_canister_id, 1, 1)?;
+
+    Ok(())
+}
+
+#[test]
+fn upgrade_job_resumes_after_upgrader_upgrade() -> Result<(), String> {
+    let pic = &PocketIc::new();
+    let (upgrader_canister_id, target_canister_id) = install_canisters(pic);
+    // Fail all installation attempts, so the job gets stuck while installing
+    set_fail_at_stage_policy(pic, upgrader_canister_id, 2);
+
+    let curr_time = pic.get_time().as_nanos_since_unix_epoch();
+    let res = try_upgrading_target_with(
+        pic,
+        "start_upgrade_job",
+        upgrader_canister_id,
+        target_canister_id,
+        curr_time + 50,
+    );
+    assert!(res.is_err(), "Upgrade job should fail to install");
+
+    let state_of_job = || -> Option<String> {
+        let response = pic
+            .query_call(
+                upgrader_canister_id,
+                Principal::anonymous(),
+                "upgrade_job_state",
+                encode_one(()).expect("Couldn't encode args"),
+            )
+            .expect("Failed to query the upgrade job state");
+        decode_one(&response).expect("Failed to decode response")
+    };
+    let state = state_of_job().expect("No upgrade job");
+    assert!(state.starts_with("Installing"), "Unexpected job state: {}", state);
+
+    // Upgrade the upgrader itself, which also resets its call chaos policy to allow all calls
+    let upgrader_wasm = std::fs::read(&*UPGRADER_WASM_PATH).expect("Failed to read Wasm file");
+    pic.upgrade_canister(upgrader_canister_id, upgrader_wasm, vec![], None)
+        .expect("Failed to upgrade the upgrader");
+
+    let curr_time = pic.get_time().as_nanos_since_unix_epoch();
+    let response = pic
+        .update_call(
+            upgrader_canister_id,
+            Principal::anonymous(),
+            "resume_upgrade_job",
+            encode_one(curr_time + 50).expect("Couldn't encode args"),
+        )
+        .expect("Failed to resume the upgrade job");
+    let res: Result<(), String> = decode_one(&response).expect("Failed to decode response");
+    assert!(res.is_ok(), "Resumed upgrade job failed: {:?}", res);
+    assert_eq!(state_of_job(), Some("Done".to_string()));
+
+    version_check(pic, target_canister_id, 2, 2)?;
+
+    Ok(())
+}
+
+#[test]
+fn rollout_halts_on_failures_and_resumes() -> Result<(), String> {
+    let pic = &PocketIc::new();
+    let (upgrader_canister_id, first_target_id) = install_canisters(pic);
+    set_policy(pic, upgrader_canister_id, "AllowEveryOther");
+
+    let target_v1_wasm_bytes =
+        std::fs::read(&*TARGET_V1_WASM_PATH).expect("Failed to read Wasm file");
+    let mut targets = vec![first_target_id];
+    for _ in 0..3 {
+        let target_canister_id = pic.create_canister();
+        pic.add_cycles(target_canister_id, 2_000_000_000_000);
+        pic.install_canister(target_canister_id, target_v1_wasm_bytes.clone(), vec![], None);
+        pic.set_controllers(
+            target_canister_id,
+            None,
+            vec![upgrader_canister_id, target_canister_id, Principal::anonymous()],
+        ).expect("Couldn't set controllers");
+        targets.push(target_canister_id);
+    }
+    // The upgrader can't upgrade the third target, as it's not its controller
+    pic.set_controllers(targets[2], None, vec![targets[2], Principal::anonymous()])
+        .expect("Couldn't set controllers");
+
+    let target_v2_wasm_bytes =
+        std::fs::read(&*TARGET_V2_WASM_PATH).expect("Failed to read Wasm file");
+    let deadline = pic.get_time().as_nanos_since_unix_epoch() + 200;
+    // One canary, then waves of two, halting on any failure
+    let response = pic
+        .update_call(
+            upgrader_canister_id,
+            Principal::anonymous(),
+            "start_rollout",
+            encode_args((targets.clone(), target_v2_wasm_bytes, 1_u64, 2_u64, 0.0_f64, deadline))
+                .expect("Couldn't encode args"),
+        )
+        .expect("Failed to start the rollout");
+    let (status, results): (String, Vec<(Principal, String)>) =
+        decode_args(&response).expect("Failed to decode response");
+    assert!(status.contains("FailureRateExceeded"), "Unexpected status: {}", status);
+    assert_eq!(results.len(), 3, "Unexpected results: {:?}", results);
+    assert_eq!(results[0], (targets[0], "Upgraded".to_string()));
+    assert_eq!(results[1], (targets[1], "Upgraded".to_string()));
+    assert_eq!(results[2].0, targets[2]);
+    assert!(
+        results[2].1.starts_with("Failed { stage: Stopping, reason: \"StopFailed"),
+        "Unexpected outcome: {}",
+        results[2].1
+    );
+    version_check(pic, targets[3], 1, 1)?;
+
+    let deadline = pic.get_time().as_nanos_since_unix_epoch() + 200;
+    let response = pic
+        .update_call(
+            upgrader_canister_id,
+            Principal::anonymous(),
+            "resume_rollout",
+            encode_one(deadline).expect("Couldn't encode args"),
+        )
+        .expect("Failed to resume the rollout");
+    let (status, results): (String, Vec<(Principal, String)>) =
+        decode_args(&response).expect("Failed to decode response");
+    assert_eq!(status, "Completed");
+    assert_eq!(results[3], (targets[3], "Upgraded".to_string()));
+    for target in [targets[0], targets[1], targets[3]] {
+        version_check(pic, target, 2, 2)?;
+    }
+    version_check(pic, targets[2], 1, 1)?;
+
+    Ok(())
+}
+
+#[test]
+fn rollout_halts_after_the_first_failed_canary() -> Result<(), String> {
+    let pic = &PocketIc::new();
+    let (upgrader_canister_id, first_target_id) = install_canisters(pic);
+    set_policy(pic, upgrader_canister_id, "AllowAll");
+
+    let target_v1_wasm_bytes =
+        std::fs::read(&*TARGET_V1_WASM_PATH).expect("Failed to read Wasm file");
+    let mut targets = vec![first_target_id];
+    for _ in 0..2 {
+        let target_canister_id = pic.create_canister();
+        pic.add_cycles(target_canister_id, 2_000_000_000_000);
+        pic.install_canister(target_canister_id, target_v1_wasm_bytes.clone(), vec![], None);
+        pic.set_controllers(
+            target_canister_id,
+            None,
+            vec![upgrader_canister_id, target_canister_id, Principal::anonymous()],
+        ).expect("Couldn't set controllers");
+        targets.push(target_canister_id);
+    }
+    // The upgrader can't upgrade the first canary, as it's not its controller
+    pic.set_controllers(targets[0], None, vec![targets[0], Principal::anonymous()])
+        .expect("Couldn't set controllers");
+
+    let target_v2_wasm_bytes =
+        std::fs::read(&*TARGET_V2_WASM_PATH).expect("Failed to read Wasm file");
+    let deadline = pic.get_time().as_nanos_since_unix_epoch() + 200;
+    // Two canaries, then waves of two, never halting because of the failure rate
+    let response = pic
+        .update_call(
+            upgrader_canister_id,
+            Principal::anonymous(),
+            "start_rollout",
+            encode_args((targets.clone(), target_v2_wasm_bytes, 2_u64, 2_u64, 1.0_f64, deadline))
+                .expect("Couldn't encode args"),
+        )
+        .expect("Failed to start the rollout");
+    let (status, results): (String, Vec<(Principal, String)>) =
+        decode_args(&response).expect("Failed to decode response");
+    assert_eq!(status, "Halted(CanaryFailed)");
+    assert_eq!(results.len(), 1, "Unexpected results: {:?}", results);
+    assert!(results[0].1.starts_with("Failed"), "Unexpected outcome: {}", results[0].1);
+    // The second canary wasn't touched
+    version_check(pic, targets[1], 1, 1)?;
+
+    // Resuming continues with the second canary, and then the rest
+    let deadline = pic.get_time().as_nanos_since_unix_epoch() + 200;
+    let response = pic
+        .update_call(
+            upgrader_canister_id,
+            Principal::anonymous(),
+            "resume_rollout",
+            encode_one(deadline).expect("Couldn't encode args"),
+        )
+        .expect("Failed to resume the rollout");
+    let (status, results): (String, Vec<(Principal, String)>) =
+        decode_args(&response).expect("Failed to decode response");
+    assert_eq!(status, "Completed");
+    assert_eq!(
+        results[1..],
+        [
+            (targets[1], "Upgraded".to_string()),
+            (targets[2], "Upgraded".to_string()),
+        ]
+    );
+    for target in [targets[1], targets[2]] {
+        version_check(pic, target, 2, 2)?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn upgrade_with_observer_reports_progress() -> Result<(), String> {
+    let pic = &PocketIc::new();
+    let (upgrader_canister_id, target_canister_id) = install_canisters(pic);
+    set_policy(pic, upgrader_canister_id, "AllowEveryOther");
+
+    let target_v2_wasm_bytes =
+        std::fs::read(&*TARGET_V2_WASM_PATH).expect("Failed to read Wasm file");
+    let curr_time = pic.get_time().as_nanos_since_unix_epoch();
+    let deadline = curr_time + 50;
+    let message_id = pic
+        .submit_call(
+            upgrader_canister_id,
+            Principal::anonymous(),
+            "try_upgrading_target_with_events",
+            encode_args((target_canister_id, target_v2_wasm_bytes, deadline))
+                .expect("Couldn't encode args"),
+        )
+        .expect("Failed to call try_upgrading_target_with_events");
+    while pic.get_time().as_nanos_since_unix_epoch() < deadline && pic.ingress_status(message_id.clone()).is_none() {
+        pic.tick();
+    }
+    let response = pic.await_call(message_id).expect("Failed to await call");
+    let (res, events): (Result<(), String>, Vec<String>) =
+        decode_args(&response).expect("Failed to decode response");
+    assert!(res.is_ok(), "Upgrade failed: {:?}", res);
+
+    // The stages are reported in order, each followed by at least one attempt
+    let stages: Vec<&String> = events.iter().filter(|e| e.starts_with("StageStarted")).collect();
+    assert_eq!(
+        stages,
+        vec![
+            "StageStarted(Stopping)",
+            "StageStarted(ObtainingInfo)",
+            "StageStarted(Installing)",
+            "StageStarted(Starting)",
+        ],
+        "Unexpected events: {:?}",
+        events
+    );
+    for stage in ["Stopping", "ObtainingInfo", "Installing", "Starting"] {
+        let attempt = format!("Attempt {{ stage: {}, attempt: 1 }}", stage);
+        assert!(events.contains(&attempt), "Missing {} in {:?}", attempt, events);
+    }
+    assert!(
+        events.iter().any(|e| e.starts_with("ObservedVersion { total_num_changes: 1,")),
+        "Missing the observed version in {:?}",
+        events
+    );
+    assert_eq!(events.last().map(String::as_str), Some("Succeeded"));
+
+    version_check(pic, target_canister_id, 2, 2)?;
+
+    Ok(())
+}
+
+#[test]
+fn concurrent_controller_change_is_reported() -> Result<(), String> {
+    let pic = &PocketIc::new();
+    let (upgrader_canister_id, target_canister_id) = install_canisters(pic);
+    // Installation attempts time out without being performed, so the upgrader keeps checking the
+    // target's history until it gives up or sees someone else's change.
+    set_policy(pic, upgrader_canister_id, "UnknownInstall");
+
+    let target_v2_wasm_bytes =
+        std::fs::read(&*TARGET_V2_WASM_PATH).expect("Failed to read Wasm file");
+    let curr_time = pic.get_time().as_nanos_since_unix_epoch();
+    let deadline = curr_time + 100;
+    let message_id = pic
+        .submit_call(
+            upgrader_canister_id,
+            Principal::anonymous(),
+            "try_upgrading_target",
+            encode_args((target_canister_id, target_v2_wasm_bytes, deadline))
+                .expect("Couldn't encode args"),
+        )
+        .expect("Failed to call try_upgrading_target");
+    for _ in 0..10 {
+        pic.tick();
+    }
+    let other_controller = Principal::from_slice(&[0xAB; 29]);
+    pic.set_controllers(
+        target_canister_id,
+        None,
+        vec![upgrader_canister_id, target_canister_id, Principal::anonymous(), other_controller],
+    ).expect("Couldn't set controllers");
+    while pic.get_time().as_nanos_since_unix_epoch() < deadline && pic.ingress_status(message_id.clone()).is_none() {
+        pic.tick();
+    }
+    let response = pic.await_call(message_id).expect("Failed to await call");
+    let res: Result<(), String> = decode_one(&response).expect("Failed to decode response");
+
+    let error = res.expect_err("The upgrade should detect the concurrent change");
+    assert!(error.contains("ConcurrentChangeDetected"), "Unexpected error: {}", error);
+    assert!(error.contains("ControllersChange"), "Missing the controllers change: {}", error);
+    assert!(error.contains("FromUser"), "Missing the origin of the change: {}", error);
+
+    Ok(())
+}
+
+#[test]
+fn upgrade_is_skipped_when_target_is_up_to_date() -> Result<(), String> {
+    let pic = &PocketIc::new();
+    let (upgrader_canister_id, target_canister_id) = install_canisters(pic);
+    set_policy(pic, upgrader_canister_id, "AllowEveryOther");
+
+    let target_v2_wasm_bytes =
+        std::fs::read(&*TARGET_V2_WASM_PATH).expect("Failed to read Wasm file");
+    let upgrade = || -> Result<String, String> {
+        let deadline = pic.get_time().as_nanos_since_unix_epoch() + 50;
+        let message_id = pic
+            .submit_call(
+                upgrader_canister_id,
+                Principal::anonymous(),
+                "try_upgrading_target_if_outdated",
+                encode_args((target_canister_id, target_v2_wasm_bytes.clone(), deadline))
+                    .expect("Couldn't encode args"),
+            )
+            .expect("Failed to call try_upgrading_target_if_outdated");
+        while pic.get_time().as_nanos_since_unix_epoch() < deadline && pic.ingress_status(message_id.clone()).is_none() {
+            pic.tick();
+        }
+        let response = pic.await_call(message_id).expect("Failed to await call");
+        decode_one(&response).expect("Failed to decode response")
+    };
+
+    assert_eq!(upgrade(), Ok("Upgraded".to_string()));
+    version_check(pic, target_canister_id, 2, 2)?;
+
+    // The second upgrade is a no-op, and doesn't touch the target
+    assert_eq!(upgrade(), Ok("AlreadyUpToDate".to_string()));
+    version_check(pic, target_canister_id, 2, 2)?;
+
+    // A stopped target is upgraded (and restarted) even if it already has the module
+    pic.stop_canister(target_canister_id, None).expect("Failed to stop target canister");
+    assert_eq!(upgrade(), Ok("Upgraded".to_string()));
+    version_check(pic, target_canister_id, 2, 3)?;
+
+    Ok(())
+}
+
+fn try_upgrading_target_with_precondition(
+    pic: &PocketIc,
+    upgrader_canister_id: Principal,
+    target_canister_id: Principal,
+    expected_num_changes: u64,
+    deadline: u64,
+) -> Result<(), String> {
+    let target_v2_wasm_bytes =
+        std::fs::read(&*TARGET_V2_WASM_PATH).expect("Failed to read Wasm file");
+    let message_id = pic
+        .submit_call(
+            upgrader_canister_id,
+            Principal::anonymous(),
+            "try_upgrading_target_with_precondition",
+            encode_args((target_canister_id, target_v2_wasm_bytes, expected_num_changes, deadline))
+                .expect("Couldn't encode args"),
+        )
+        .expect("Failed to call try_upgrading_target_with_precondition");
+    while pic.get_time().as_nanos_since_unix_epoch() < deadline && pic.ingress_status(message_id.clone()).is_none() {
+        pic.tick();
+    }
+    let response = pic.await_call(message_id).expect("Failed to await call");
+    decode_one(&response).expect("Failed to decode response")
+}
+
+#[test]
+fn precondition_prevents_upgrading_a_changed_target() -> Result<(), String> {
+    let pic = &PocketIc::new();
+    let (upgrader_canister_id, target_canister_id) = install_canisters(pic);
+    set_policy(pic, upgrader_canister_id, "AllowEveryOther");
+
+    // The target has a creation, an installation and a controllers change in its history
+    let info_version = 3;
+
+    let curr_time = pic.get_time().as_nanos_since_unix_epoch();
+    let res = try_upgrading_target_with_precondition(pic, upgrader_canister_id, target_canister_id, info_version - 1, curr_time + 50);
+    assert!(
+        res.as_ref().is_err_and(|e| e.contains("PreconditionFailed") && e.contains(&format!("actual_version: {}", info_version))),
+        "The upgrade should fail the precondition: {:?}",
+        res
+    );
+    pic.start_canister(target_canister_id, None).expect("Failed to start target canister");
+    version_check(pic, target_canister_id, 1, 1)?;
+
+    let curr_time = pic.get_time().as_nanos_since_unix_epoch();
+    let res = try_upgrading_target_with_precondition(pic, upgrader_canister_id, target_canister_id, info_version, curr_time + 50);
+    assert!(res.is_ok(), "Upgrade failed: {:?}", res);
+    version_check(pic, target_canister_id, 2, 2)?;
+
+    Ok(())
+}
+
+#[test]
+fn stuck_stop_is_detected_and_target_restarted() -> Result<(), String> {
+    let pic = &PocketIc::new();
+    let (upgrader_canister_id, target_canister_id) = install_canisters(pic);
+    set_policy(pic, upgrader_canister_id, "AllowAll");
+
+    // Keep a call context open on the target, so that it can't stop
+    let blocked_call = pic
+        .submit_call(
+            target_canister_id,
+            Principal::anonymous(),
+            "call_and_wait",
+            encode_args((upgrader_canister_id, "block_until_released".to_string()))
+                .expect("Couldn't encode args"),
+        )
+        .expect("Failed to call call_and_wait");
+    for _ in 0..5 {
+        pic.tick();
+    }
+
+    let target_v2_wasm_bytes =
+        std::fs::read(&*TARGET_V2_WASM_PATH).expect("Failed to read Wasm file");
+    let curr_time = pic.get_time().as_nanos_since_unix_epoch();
+    let message_id = pic
+        .submit_call(
+            upgrader_canister_id,
+            Principal::anonymous(),
+            "try_upgrading_target_restarting_on_stop_failure",
+            encode_args((target_canister_id, target_v2_wasm_bytes, curr_time + 50))
+                .expect("Couldn't encode args"),
+        )
+        .expect("Failed to call try_upgrading_target_restarting_on_stop_failure");
+    for _ in 0..10 {
+        pic.tick();
+    }
+    // Let the pending bounded-wait stop call time out; the deadline has passed by then
+    pic.advance_time(std::time::Duration::from_secs(10 * 60));
+    let res: Result<(), String> =
+        decode_one(&pic.await_call(message_id).expect("Failed to await call")).expect("Failed to decode response");
+    let error = res.expect_err("The upgrade should fail to stop the target");
+    assert!(error.contains("StopFailed"), "Unexpected error: {}", error);
+    assert!(error.contains("stuck: true"), "The stop should be stuck: {}", error);
+    assert!(error.contains("final_status: Some(Running)"), "The target should be restarted: {}", error);
+
+    // The target was restarted, so it accepts calls again
+    version_check(pic, target_canister_id, 1, 1)?;
+
+    pic.update_call(
+        upgrader_canister_id,
+        Principal::anonymous(),
+        "release_blocked_calls",
+        encode_args(()).expect("Couldn't encode args"),
+    )
+    .expect("Failed to release the blocked calls");
+    pic.await_call(blocked_call).expect("Failed to await call");
+
+    Ok(())
+}
+
+fn submit_locked_upgrade(
+    pic: &PocketIc,
+    upgrader_canister_id: Principal,
+    target_canister_id: Principal,
+    wait: bool,
+    deadline: u64,
+) -> pocket_ic::common::rest::RawMessageId {
+    let target_v2_wasm_bytes =
+        std::fs::read(&*TARGET_V2_WASM_PATH).expect("Failed to read Wasm file");
+    pic.submit_call(
+        upgrader_canister_id,
+        Principal::anonymous(),
+        "try_upgrading_target_locked",
+        encode_args((target_canister_id, target_v2_wasm_bytes, wait, deadline))
+            .expect("Couldn't encode args"),
+    )
+    .expect("Failed to call try_upgrading_target_locked")
+}
+
+fn in_flight_upgrades(pic: &PocketIc, upgrader_canister_id: Principal) -> Vec<Principal> {
+    let response = pic
+        .query_call(
+            upgrader_canister_id,
+            Principal::anonymous(),
+            "in_flight_upgrades",
+            encode_args(()).expect("Couldn't encode args"),
+        )
+        .expect("Failed to call in_flight_upgrades");
+    decode_one(&response).expect("Failed to decode response")
+}
+
+#[test]
+fn upgrade_lock_rejects_or_queues_concurrent_upgrades() -> Result<(), String> {
+    let pic = &PocketIc::new();
+    let (upgrader_canister_id, target_canister_id) = install_canisters(pic);
+    set_policy(pic, upgrader_canister_id, "AllowAll");
+
+    // Without waiting, the second upgrade is rejected while the first one is in flight
+    let deadline = pic.get_time().as_nanos_since_unix_epoch() + 100;
+    let first = submit_locked_upgrade(pic, upgrader_canister_id, target_canister_id, false, deadline);
+    pic.tick();
+    assert_eq!(in_flight_upgrades(pic, upgrader_canister_id), vec![target_canister_id]);
+    let second = submit_locked_upgrade(pic, upgrader_canister_id, target_canister_id, false, deadline);
+    let second_res: Result<(), String> =
+        decode_one(&pic.await_call(second).expect("Failed to await call")).expect("Failed to decode response");
+    assert!(
+        second_res.as_ref().is_err_and(|e| e.contains("UpgradeInProgress")),
+        "The second upgrade should be rejected: {:?}",
+        second_res
+    );
+    let first_res: Result<(), String> =
+        decode_one(&pic.await_call(first).expect("Failed to await call")).expect("Failed to decode response");
+    assert!(first_res.is_ok(), "Upgrade failed: {:?}", first_res);
+    assert_eq!(in_flight_upgrades(pic, upgrader_canister_id), vec![]);
+    version_check(pic, target_canister_id, 2, 2)?;
+
+    // When waiting, the second upgrade runs after the first one
+    let deadline = pic.get_time().as_nanos_since_unix_epoch() + 200;
+    let first = submit_locked_upgrade(pic, upgrader_canister_id, target_canister_id, true, deadline);
+    pic.tick();
+    let second = submit_locked_upgrade(pic, upgrader_canister_id, target_canister_id, true, deadline);
+    for message_id in [first, second] {
+        let res: Result<(), String> =
+            decode_one(&pic.await_call(message_id).expect("Failed to await call")).expect("Failed to decode response");
+        assert!(res.is_ok(), "Upgrade failed: {:?}", res);
+    }
+    assert_eq!(in_flight_upgrades(pic, upgrader_canister_id), vec![]);
+    version_check(pic, target_canister_id, 2, 4)?;
+
+    Ok(())
+}
+
+fn try_upgrading_target_with_validated_arg(
+    pic: &PocketIc,
+    upgrader_canister_id: Principal,
+    target_canister_id: Principal,
+    wasm: Vec<u8>,
+    arg: Vec<u8>,
+) -> Result<(), String> {
+    let deadline = pic.get_time().as_nanos_since_unix_epoch() + 50;
+    let response = pic
+        .update_call(
+            upgrader_canister_id,
+            Principal::anonymous(),
+            "try_upgrading_target_with_validated_arg",
+            encode_args((target_canister_id, wasm, arg, deadline))
+                .expect("Couldn't encode args"),
+        )
+        .expect("Failed to call try_upgrading_target_with_validated_arg");
+    decode_one(&response).expect("Failed to decode response")
+}
+
+#[test]
+fn invalid_argument_is_rejected_before_stopping() -> Result<(), String> {
+    let pic = &PocketIc::new();
+    let (upgrader_canister_id, target_canister_id) = install_canisters(pic);
+    set_policy(pic, upgrader_canister_id, "AllowEveryOther");
+    let target_v2_wasm_bytes =
+        std::fs::read(&*TARGET_V2_WASM_PATH).expect("Failed to read Wasm file");
+    let wasm = with_candid_service(
+        &target_v2_wasm_bytes,
+        "service : (record { limit : nat }) -> { version : () -> (nat32) }",
+    );
+
+    let res = try_upgrading_target_with_validated_arg(pic, upgrader_canister_id, target_canister_id, wasm.clone(), vec![1, 2, 3]);
+    assert!(
+        res.as_ref().is_err_and(|e| e.contains("PreFlight") && e.contains("MalformedArg")),
+        "A malformed argument should be rejected: {:?}",
+        res
+    );
+    // The target wasn't touched, so it's still running the old version
+    version_check(pic, target_canister_id, 1, 1)?;
+
+    let res = try_upgrading_target_with_validated_arg(pic, upgrader_canister_id, target_canister_id, wasm, encode_args(("not a record",)).expect("Couldn't encode args"));
+    assert!(
+        res.as_ref().is_err_and(|e| e.contains("PreFlight") && e.contains("TypeMismatch")),
+        "An argument of the wrong type should be rejected: {:?}",
+        res
+    );
+    version_check(pic, target_canister_id, 1, 1)?;
+
+    let res = try_upgrading_target_with_validated_arg(pic, upgrader_canister_id, target_canister_id, target_v2_wasm_bytes.clone(), vec![]);
+    assert!(
+        res.as_ref().is_err_and(|e| e.contains("PreFlight") && e.contains("MissingInterface")),
+        "A module without an interface should be rejected: {:?}",
+        res
+    );
+    version_check(pic, target_canister_id, 1, 1)?;
+
+    // An empty argument stands for no arguments, which is fine if the init argument is optional
+    let wasm = with_candid_service(
+        &target_v2_wasm_bytes,
+        "service : (opt nat) -> { version : () -> (nat32) }",
+    );
+    let res = try_upgrading_target_with_validated_arg(pic, upgrader_canister_id, target_canister_id, wasm, vec![]);
+    assert!(res.is_ok(), "Upgrade failed: {:?}", res);
+    version_check(pic, target_canister_id, 2, 2)?;
+
+    Ok(())
+}
+
+fn try_upgrading_target_with_module_checks(
+    pic: &PocketIc,
+    upgrader_canister_id: Principal,
+    target_canister_id: Principal,
+    wasm: Vec<u8>,
+    required_update_methods: Vec<&str>,
+) -> Result<(), String> {
+    let deadline = pic.get_time().as_nanos_since_unix_epoch() + 50;
+    let required_update_methods: Vec<String> =
+        required_update_methods.into_iter().map(String::from).collect();
+    let response = pic
+        .update_call(
+            upgrader_canister_id,
+            Principal::anonymous(),
+            "try_upgrading_target_with_module_checks",
+            encode_args((target_canister_id, wasm, required_update_methods, deadline))
+                .expect("Couldn't encode args"),
+        )
+        .expect("Failed to call try_upgrading_target_with_module_checks");
+    decode_one(&response).expect("Failed to decode response")
+}
+
+#[test]
+fn invalid_module_is_rejected_before_stopping() -> Result<(), String> {
+    let pic = &PocketIc::new();
+    let (upgrader_canister_id, target_canister_id) = install_canisters(pic);
+    set_policy(pic, upgrader_canister_id, "AllowEveryOther");
+    let target_v2_wasm_bytes =
+        std::fs::read(&*TARGET_V2_WASM_PATH).expect("Failed to read Wasm file");
+
+    let res = try_upgrading_target_with_module_checks(pic, upgrader_canister_id, target_canister_id, target_v2_wasm_bytes.clone(), vec!["version", "no_such_method"]);
+    assert!(
+        res.as_ref().is_err_and(|e| e.contains("PreFlight") && e.contains("MissingUpdateMethod")),
+        "A module without a required method should be rejected: {:?}",
+        res
+    );
+    version_check(pic, target_canister_id, 1, 1)?;
+
+    let res = try_upgrading_target_with_module_checks(pic, upgrader_canister_id, target_canister_id, vec![0, 1, 2, 3], vec![]);
+    assert!(
+        res.as_ref().is_err_and(|e| e.contains("InvalidModule")),
+        "Garbage should be rejected: {:?}",
+        res
+    );
+    version_check(pic, target_canister_id, 1, 1)?;
+
+    // Gzipped modules are inspected after decompression
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(&target_v2_wasm_bytes).expect("Couldn't gzip the module");
+    let gzipped = encoder.finish().expect("Couldn't gzip the module");
+    let res = try_upgrading_target_with_module_checks(pic, upgrader_canister_id, target_canister_id, gzipped, vec!["version"]);
+    assert!(res.is_ok(), "Upgrade failed: {:?}", res);
+    version_check(pic, target_canister_id, 2, 2)?;
+
+    Ok(())
+}
+
+fn try_upgrading_target_with_interface_check(
+    pic: &PocketIc,
+    upgrader_canister_id: Principal,
+    target_canister_id: Principal,
+    wasm: Vec<u8>,
+    old_interface: &str,
+    warn_only: bool,
+) -> Result<(), String> {
+    let deadline = pic.get_time().as_nanos_since_unix_epoch() + 50;
+    let response = pic
+        .update_call(
+            upgrader_canister_id,
+            Principal::anonymous(),
+            "try_upgrading_target_with_interface_check",
+            encode_args((target_canister_id, wasm, old_interface, warn_only, deadline))
+                .expect("Couldn't encode args"),
+        )
+        .expect("Failed to call try_upgrading_target_with_interface_check");
+    decode_one(&response).expect("Failed to decode response")
+}
+
+#[test]
+fn interface_check_refuses_or_warns() -> Result<(), String> {
+    let pic = &PocketIc::new();
+    let (upgrader_canister_id, target_canister_id) = install_canisters(pic);
+    set_policy(pic, upgrader_canister_id, "AllowEveryOther");
+    let old_interface = "service : { version : () -> (nat32) }";
+    let target_v2_wasm_bytes =
+        std::fs::read(&*TARGET_V2_WASM_PATH).expect("Failed to read Wasm file");
+
+    // Changing the result type of an existing method breaks clients
+    let incompatible = with_candid_service(&target_v2_wasm_bytes, "service : { version : () -> (text) }");
+    let res = try_upgrading_target_with_interface_check(pic, upgrader_canister_id, target_canister_id, incompatible, old_interface, false);
+    assert!(
+        res.as_ref().is_err_and(|e| e.contains("PreFlight") && e.contains("Incompatible")),
+        "An incompatible interface should be refused: {:?}",
+        res
+    );
+    version_check(pic, target_canister_id, 1, 1)?;
+
+    // Without candid:service metadata, the check can't pass
+    let res = try_upgrading_target_with_interface_check(pic, upgrader_canister_id, target_canister_id, target_v2_wasm_bytes.clone(), old_interface, false);
+    assert!(
+        res.as_ref().is_err_and(|e| e.contains("PreFlight") && e.contains("MissingInterface")),
+        "The upgrade should be refused: {:?}",
+        res
+    );
+    version_check(pic, target_canister_id, 1, 1)?;
+
+    let res = try_upgrading_target_with_interface_check(pic, upgrader_canister_id, target_canister_id, target_v2_wasm_bytes.clone(), old_interface, true);
+    assert!(res.is_ok(), "Upgrade failed despite warn_only: {:?}", res);
+    version_check(pic, target_canister_id, 2, 2)?;
+
+    // Adding a method is a compatible change
+    let compatible = with_candid_service(
+        &target_v2_wasm_bytes,
+        "service : { version : () -> (nat32); self_history : () -> (vec blob) }",
+    );
+    let res = try_upgrading_target_with_interface_check(pic, upgrader_canister_id, target_canister_id, compatible, old_interface, false);
+    assert!(res.is_ok(), "Upgrade with a compatible interface failed: {:?}", res);
+    version_check(pic, target_canister_id, 2, 3)?;
+
+    Ok(())
+}
+
+#[test]
+fn upgrade_via_proxy_works_with_allow_every_other_policy() -> Result<(), String> {
+    let pic = &PocketIc::new();
+    let (upgrader_canister_id, target_canister_id) = install_canisters(pic);
+    set_policy(pic, upgrader_canister_id, "AllowEveryOther");
+
+    // A second upgrader acts as the proxy, and is the only canister controlling the target
+    let proxy_canister_id = pic.create_canister();
+    pic.add_cycles(proxy_canister_id, 2_000_000_000_000);
+    let upgrader_wasm = std::fs::read(&*UPGRADER_WASM_PATH).expect("Failed to read Wasm file");
+    pic.install_canister(proxy_canister_id, upgrader_wasm, vec![], None);
+    pic.set_controllers(
+        target_canister_id,
+        None,
+        vec![proxy_canister_id, Principal::anonymous()],
+    ).expect("Couldn't set controllers");
+
+    let curr_time = pic.get_time().as_nanos_since_unix_epoch();
+    let res = try_upgrading_target(pic, upgrader_canister_id, target_canister_id, curr_time + 50);
+    assert!(res.is_err(), "Upgrade without the proxy should fail: {:?}", res);
+    pic.start_canister(target_canister_id, None).expect("Failed to start target canister");
+
+    let target_v2_wasm_bytes =
+        std::fs::read(&*TARGET_V2_WASM_PATH).expect("Failed to read Wasm file");
+    let curr_time = pic.get_time().as_nanos_since_unix_epoch();
+    let deadline = curr_time + 50;
+    let message_id = pic
+        .submit_call(
+            upgrader_canister_id,
+            Principal::anonymous(),
+            "try_upgrading_target_via_proxy",
+            encode_args((target_canister_id, target_v2_wasm_bytes, proxy_canister_id, deadline))
+                .expect("Couldn't encode args"),
+        )
+        .expect("Failed to call try_upgrading_target_via_proxy");
+    while pic.get_time().as_nanos_since_unix_epoch() < deadline && pic.ingress_status(message_id.clone()).is_none() {
+        pic.tick();
+    }
+    let res: Result<(), String> =
+        decode_one(&pic.await_call(message_id).expect("Failed to await call")).expect("Failed to decode response");
+    assert!(res.is_ok(), "Upgrade via proxy failed: {:?}", res);
+
+    version_check(pic, target_canister_id, 2, 2)?;
+
+    Ok(())
+}
+
+fn try_deploying_target(
+    pic: &PocketIc,
+    upgrader_canister_id: Principal,
+    target_canister_id: Principal,
+    wasm: Vec<u8>,
+    mode: &str,
+    deadline: u64,
+) -> Result<(), String> {
+    let message_id = pic
+        .submit_call(
+            upgrader_canister_id,
+            Principal::anonymous(),
+            "try_deploying_target",
+            encode_args((target_canister_id, wasm, mode, deadline)).expect("Couldn't encode args"),
+        )
+        .expect("Failed to call try_deploying_target");
+
+    while pic.get_time().as_nanos_since_unix_epoch() < deadline && pic.ingress_status(message_id.clone()).is_none() {
+        pic.tick();
+    }
+
+    let response = pic.await_call(message_id).expect("Failed to await call");
+    decode_one(&response).expect("Failed to decode response")
+}
+
+#[test]
+fn install_and_reinstall_work_with_allow_every_other_policy() -> Result<(), String> {
+    let pic = &PocketIc::new();
+    let (upgrader_canister_id, _) = install_canisters(pic);
+    set_policy(pic, upgrader_canister_id, "AllowEveryOther");
+
+    let empty_canister_id = pic.create_canister();
+    pic.add_cycles(empty_canister_id, 2_000_000_000_000);
+    pic.set_controllers(
+        empty_canister_id,
+        None,
+        vec![upgrader_canister_id, empty_canister_id, Principal::anonymous()],
+    ).expect("Couldn't set controllers");
+
+    let v1_wasm = std::fs::read(&*TARGET_V1_WASM_PATH).expect("Failed to read Wasm file");
+    let v2_wasm = std::fs::read(&*TARGET_V2_WASM_PATH).expect("Failed to read Wasm file");
+
+    let curr_time = pic.get_time().as_nanos_since_unix_epoch();
+    let res = try_deploying_target(pic, upgrader_canister_id, empty_canister_id, v1_wasm.clone(), "install", curr_time + 50);
+    assert!(res.is_ok(), "Install failed: {:?}", res);
+    version_check(pic, empty_canister_id, 1, 1)?;
+
+    // Installing into a non-empty canister is refused
+    let curr_time = pic.get_time().as_nanos_since_unix_epoch();
+    let res = try_deploying_target(pic, upgrader_canister_id, empty_canister_id, v1_wasm, "install", curr_time + 50);
+    assert!(
+        res.as_ref().is_err_and(|e| e.contains("ModuleAlreadyInstalled")),
+        "Install into a non-empty canister should fail: {:?}",
+        res
+    );
+    pic.start_canister(empty_canister_id, None).expect("Failed to start target canister");
+    version_check(pic, empty_canister_id, 1, 1)?;
+
+    let curr_time = pic.get_time().as_nanos_since_unix_epoch();
+    let res = try_deploying_target(pic, upgrader_canister_id, empty_canister_id, v2_wasm, "reinstall", curr_time + 50);
+    assert!(res.is_ok(), "Reinstall failed: {:?}", res);
+    version_check(pic, empty_canister_id, 2, 2)?;
+
+    Ok(())
+}
+
+fn try_lifecycle_operation(
+    pic: &PocketIc,
+    upgrader_canister_id: Principal,
+    target_canister_id: Principal,
+    operation: &str,
+    amount: u128,
+) -> Result<(), String> {
+    let deadline = pic.get_time().as_nanos_since_unix_epoch() + 50;
+    let response = pic
+        .update_call(
+            upgrader_canister_id,
+            Principal::anonymous(),
+            "try_lifecycle_operation",
+            encode_args((target_canister_id, operation, amount, deadline)).expect("Couldn't encode args"),
+        )
+        .expect("Failed to call try_lifecycle_operation");
+    decode_one(&response).expect("Failed to decode response")
+}
+
+#[test]
+fn lifecycle_operations_work_with_allow_every_other_policy() -> Result<(), String> {
+    let pic = &PocketIc::new();
+    let (upgrader_canister_id, _) = install_canisters(pic);
+    set_policy(pic, upgrader_canister_id, "AllowEveryOther");
+
+    let deadline = pic.get_time().as_nanos_since_unix_epoch() + 50;
+    let response = pic
+        .update_call(
+            upgrader_canister_id,
+            Principal::anonymous(),
+            "try_creating_canister",
+            encode_args((vec![Principal::anonymous()], 100_000_000_000u128, deadline)).expect("Couldn't encode args"),
+        )
+        .expect("Failed to call try_creating_canister");
+    let res: Result<Principal, String> = decode_one(&response).expect("Failed to decode response");
+    let new_canister_id = res?;
+    assert!(pic.canister_exists(new_canister_id));
+    let mut controllers = pic.get_controllers(new_canister_id);
+    controllers.sort();
+    let mut expected = vec![upgrader_canister_id, Principal::anonymous()];
+    expected.sort();
+    assert_eq!(controllers, expected);
+
+    try_lifecycle_operation(pic, upgrader_canister_id, new_canister_id, "update_settings", 1234)?;
+    let status = pic
+        .canister_status(new_canister_id, Some(Principal::anonymous()))
+        .expect("Failed to get the canister status");
+    assert_eq!(status.settings.freezing_threshold, candid::Nat::from(1234u32));
+
+    let balance = pic.cycle_balance(new_canister_id);
+    try_lifecycle_operation(pic, upgrader_canister_id, new_canister_id, "deposit_cycles", 10_000_000_000)?;
+    assert!(pic.cycle_balance(new_canister_id) > balance);
+
+    let target_v1_wasm = std::fs::read(&*TARGET_V1_WASM_PATH).expect("Failed to read Wasm file");
+    pic.install_canister(new_canister_id, target_v1_wasm, vec![], Some(Principal::anonymous()));
+    try_lifecycle_operation(pic, upgrader_canister_id, new_canister_id, "uninstall_code", 0)?;
+    let status = pic
+        .canister_status(new_canister_id, Some(Principal::anonymous()))
+        .expect("Failed to get the canister status");
+    assert_eq!(status.module_hash, None);
+
+    try_lifecycle_operation(pic, upgrader_canister_id, new_canister_id, "delete_canister", 0)?;
+    assert!(!pic.canister_exists(new_canister_id));
+
+    Ok(())
+}
+
+#[test]
+fn delete_canister_succeeds_when_a_delayed_attempt_deletes_the_target() -> Result<(), String> {
+    let pic = &PocketIc::new();
+    let (upgrader_canister_id, target_canister_id) = install_canisters(pic);
+    // The first deletion times out, and only goes through right before the second one, which
+    // then fails because the target is already gone
+    pic.update_call(
+        upgrader_canister_id,
+        Principal::anonymous(),
+        "set_delayed_first_delete_policy",
+        encode_one(target_canister_id).expect("Couldn't encode args"),
+    )
+    .expect("Failed to set the policy");
+
+    try_lifecycle_operation(pic, upgrader_canister_id, target_canister_id, "delete_canister", 0)?;
+    assert!(!pic.canister_exists(target_canister_id));
+
+    Ok(())
+}
+
+fn try_changing_controllers(
+    pic: &PocketIc,
+    upgrader_canister_id: Principal,
+    target_canister_id: Principal,
+    add: Vec<Principal>,
+    remove: Vec<Principal>,
+    allow_removing_self: bool,
+) -> Result<Vec<Principal>, String> {
+    let deadline = pic.get_time().as_nanos_since_unix_epoch() + 50;
+    let response = pic
+        .update_call(
+            upgrader_canister_id,
+            Principal::anonymous(),
+            "try_changing_controllers",
+            encode_args((target_canister_id, add, remove, allow_removing_self, deadline))
+                .expect("Couldn't encode args"),
+        )
+        .expect("Failed to call try_changing_controllers");
+    decode_one(&response).expect("Failed to decode response")
+}
+
+fn sorted(mut principals: Vec<Principal>) -> Vec<Principal> {
+    principals.sort();
+    principals
+}
+
+#[test]
+fn controller_changes_are_resolved_and_guarded() -> Result<(), String> {
+    let pic = &PocketIc::new();
+    let (upgrader_canister_id, target_canister_id) = install_canisters(pic);
+    let new_controller = Principal::from_slice(&[1, 2, 3]);
+
+    // The first update times out without being applied; re-reading the controllers shows that
+    // the change has to be retried
+    set_policy(pic, upgrader_canister_id, "UnknownFirstUpdateSettings");
+    let res = try_changing_controllers(
+        pic,
+        upgrader_canister_id,
+        target_canister_id,
+        vec![new_controller],
+        vec![Principal::anonymous()],
+        false,
+    )?;
+    let expected = sorted(vec![upgrader_canister_id, target_canister_id, new_controller]);
+    assert_eq!(sorted(res), expected);
+    assert_eq!(sorted(pic.get_controllers(target_canister_id)), expected);
+
+    // Removing the upgrader itself is refused unless explicitly allowed
+    set_policy(pic, upgrader_canister_id, "AllowEveryOther");
+    let res = try_changing_controllers(pic, upgrader_canister_id, target_canister_id, vec![], vec![upgrader_canister_id], false);
+    assert!(
+        res.as_ref().is_err_and(|e| e.contains("WouldRemoveSelf")),
+        "Removing the upgrader should be refused: {:?}",
+        res
+    );
+    assert_eq!(sorted(pic.get_controllers(target_canister_id)), expected);
+
+    let res = try_changing_controllers(pic, upgrader_canister_id, target_canister_id, vec![], vec![upgrader_canister_id], true)?;
+    let expected = sorted(vec![target_canister_id, new_controller]);
+    assert_eq!(sorted(res), expected);
+    assert_eq!(sorted(pic.get_controllers(target_canister_id)), expected);
+
+    Ok(())
+}
+
+/// Appends a custom section with `size` bytes of padding to the module, to make it too large
+/// to be installed in a single message.
+fn pad_wasm(mut wasm: Vec<u8>, size: usize) -> Vec<u8> {
+    fn leb128(mut value: usize, out: &mut Vec<u8>) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(byte);
+                break;
+            }
+            out.push(byte | 0x80);
+        }
+    }
+    let name = b"padding";
+    let mut payload = vec![];
+    leb128(name.len(), &mut payload);
+    payload.extend_from_slice(name);
+    payload.extend(std::iter::repeat_n(0xab, size));
+    wasm.push(0); // custom section id
+    leb128(payload.len(), &mut wasm);
+    wasm.extend(payload);
+    wasm
+}
+
+#[test]
+fn auto_upgrade_works_for_small_and_large_modules() -> Result<(), String> {
+    let pic = &PocketIc::new();
+    let (upgrader_canister_id, target_canister_id) = install_canisters(pic);
+    set_policy(pic, upgrader_canister_id, "AllowEveryOther");
+
+    let small_wasm = std::fs::read(&*TARGET_V2_WASM_PATH).expect("Failed to read Wasm file");
+    let large_wasm = pad_wasm(small_wasm.clone(), 3 * 1024 * 1024);
+
+    for (wasm, expected_total_versions) in [(small_wasm, 2), (large_wasm, 3)] {
+        let curr_time = pic.get_time().as_nanos_since_unix_epoch();
+        let res = try_upgrading_target_to(
+            pic,
+            "try_upgrading_target_auto",
+            upgrader_canister_id,
+            target_canister_id,
+            wasm,
+            curr_time + 200,
+        );
+        assert!(res.is_ok(), "Upgrade failed: {:?}", res);
+        version_check(pic, target_canister_id, 2, expected_total_versions)?;
+    }
+
+    Ok(())
+}
+
 fn set_fail_at_stage_policy(pic: &PocketIc, canister_id: Principal, stage: u32) -> () {
     pic.update_call(
         canister_id,
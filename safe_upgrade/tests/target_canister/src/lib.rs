@@ -1,5 +1,33 @@
-use ic_cdk::update;
+use candid::Principal;
+use ic_cdk::call::Call;
+use ic_cdk::{pre_upgrade, update};
 use ic_cdk::management_canister::{canister_info, CanisterInfoArgs, ChangeDetails};
+use std::cell::Cell;
+
+thread_local! {
+    static TRAP_IN_PRE_UPGRADE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Makes the `pre_upgrade` hook trap, to simulate a canister that can only be upgraded
+/// with `skip_pre_upgrade`.
+#[update]
+fn set_trap_in_pre_upgrade(trap: bool) {
+    TRAP_IN_PRE_UPGRADE.with(|t| t.set(trap));
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    if TRAP_IN_PRE_UPGRADE.with(|t| t.get()) {
+        ic_cdk::trap("pre_upgrade trapped as requested");
+    }
+}
+
+/// Calls the given method and waits for the response, keeping a call context open until then.
+/// Stopping the canister can't complete while the call is pending.
+#[update]
+async fn call_and_wait(callee: Principal, method: String) {
+    let _ = Call::unbounded_wait(callee, &method).await;
+}
 
 #[update]
 fn version() -> u32 {
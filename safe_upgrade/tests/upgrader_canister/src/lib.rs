@@ -1,9 +1,20 @@
 use candid::Principal;
-use ic_cdk::call::{CallFailed, CallRejected, OnewayError};
+use std::cell::{Cell, RefCell};
+use ic_cdk::call::{CallFailed, CallRejected, OnewayError, RejectCode};
 use ic_call_chaos::{set_policy as cc_set_policy, Call, Policy};
-use ic_call_retry::{when_out_of_time_or_stopping, Deadline};
-use ic_cdk::update;
-use ic_safe_upgrades::{upgrade_canister, WasmModule, UpgradeStage};
+use ic_call_retry::{
+    call_idempotent_method_with_retry, when_out_of_time_or_stopping, Call as RetryCall, Deadline,
+};
+use ic_cdk::management_canister::{CanisterInfoArgs, CanisterSettings, DeleteCanisterArgs};
+use ic_cdk::storage::{stable_restore, stable_save};
+use ic_cdk::{post_upgrade, pre_upgrade, query, update};
+use ic_safe_upgrades::{
+    change_controllers, create_canister, delete_canister, deposit_cycles, install_canister, reinstall_canister,
+    uninstall_code, update_settings, upgrade_canister, upgrade_canister_with_observer,
+    upgrade_canister_with_rollback, upload_chunks, ControllerChange, HealthCheck, InterfaceCheck, InterfaceSource, ManagementProxy, ModuleChecks, RelayArgs, Rollout, RolloutConfig,
+    SkipIfUpToDate, UpgradeJob, UpgradeOptions, UpgradePrecondition, UpgradeStage,
+    WasmModule,
+};
 
 #[update]
 pub async fn try_upgrading_target(
@@ -15,12 +26,632 @@ pub async fn try_upgrading_target(
         target_canister,
         WasmModule::Bytes(new_wasm),
         vec![],
+        UpgradeOptions::default(),
         &mut when_out_of_time_or_stopping(&Deadline::TimeOrStopping(deadline)),
     )
     .await
+    .map(|_| ())
     .map_err(|e| format!("Failed to upgrade canister: {:?}", e))
 }
 
+#[update]
+pub async fn try_upgrading_target_skipping_pre_upgrade(
+    target_canister: Principal,
+    new_wasm: Vec<u8>,
+    deadline: u64,
+) -> Result<(), String> {
+    upgrade_canister(
+        target_canister,
+        WasmModule::Bytes(new_wasm),
+        vec![],
+        UpgradeOptions {
+            skip_pre_upgrade: true,
+            ..Default::default()
+        },
+        &mut when_out_of_time_or_stopping(&Deadline::TimeOrStopping(deadline)),
+    )
+    .await
+    .map(|_| ())
+    .map_err(|e| format!("Failed to upgrade canister: {:?}", e))
+}
+
+/// Upgrades the target, and checks that the upgraded target reports the expected version.
+#[update]
+pub async fn try_upgrading_target_with_health_check(
+    target_canister: Principal,
+    new_wasm: Vec<u8>,
+    expected_version: u32,
+    deadline: u64,
+) -> Result<(), String> {
+    upgrade_canister(
+        target_canister,
+        WasmModule::Bytes(new_wasm),
+        vec![],
+        UpgradeOptions {
+            health_check: Some(HealthCheck::new("version", &expected_version)),
+            ..Default::default()
+        },
+        &mut when_out_of_time_or_stopping(&Deadline::TimeOrStopping(deadline)),
+    )
+    .await
+    .map(|_| ())
+    .map_err(|e| format!("Failed to upgrade canister: {:?}", e))
+}
+
+/// Upgrades the target, checking that the upgraded target reports the expected version, and
+/// rolling back otherwise.
+#[update]
+pub async fn try_upgrading_target_with_rollback(
+    target_canister: Principal,
+    new_wasm: Vec<u8>,
+    expected_version: u32,
+    deadline: u64,
+) -> Result<(), String> {
+    let health_check = || async move {
+        let mut stop_trying = when_out_of_time_or_stopping(&Deadline::TimeOrStopping(deadline));
+        let version: u32 = call_idempotent_method_with_retry(
+            RetryCall::bounded_wait(target_canister, "version"),
+            &mut stop_trying,
+        )
+        .await
+        .map_err(|e| format!("Couldn't get the version: {:?}", e))?
+        .candid()
+        .map_err(|e| format!("Couldn't decode the version: {:?}", e))?;
+        if version == expected_version {
+            Ok(())
+        } else {
+            Err(format!("Expected version {}, got {}", expected_version, version))
+        }
+    };
+    upgrade_canister_with_rollback(
+        target_canister,
+        WasmModule::Bytes(new_wasm),
+        vec![],
+        UpgradeOptions::default(),
+        health_check,
+        &mut when_out_of_time_or_stopping(&Deadline::TimeOrStopping(deadline)),
+        &mut when_out_of_time_or_stopping(&Deadline::TimeOrStopping(deadline)),
+    )
+    .await
+    .map(|_| ())
+    .map_err(|e| format!("Failed to upgrade canister: {:?}", e))
+}
+
+/// Upgrades the target with rollback, requiring the target to have the given number of changes.
+#[update]
+pub async fn try_upgrading_target_with_rollback_and_precondition(
+    target_canister: Principal,
+    new_wasm: Vec<u8>,
+    expected_num_changes: u64,
+    deadline: u64,
+) -> Result<(), String> {
+    upgrade_canister_with_rollback(
+        target_canister,
+        WasmModule::Bytes(new_wasm),
+        vec![],
+        UpgradeOptions {
+            precondition: Some(UpgradePrecondition {
+                total_num_changes: Some(expected_num_changes),
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        || async { Ok(()) },
+        &mut when_out_of_time_or_stopping(&Deadline::TimeOrStopping(deadline)),
+        &mut when_out_of_time_or_stopping(&Deadline::TimeOrStopping(deadline)),
+    )
+    .await
+    .map(|_| ())
+    .map_err(|e| format!("Failed to upgrade canister: {:?}", e))
+}
+
+/// Uploads the new WASM into the target's own chunk store, and then upgrades the target
+/// using a chunked install.
+#[update]
+pub async fn try_upgrading_target_chunked(
+    target_canister: Principal,
+    new_wasm: Vec<u8>,
+    deadline: u64,
+) -> Result<(), String> {
+    let mut stop_trying = when_out_of_time_or_stopping(&Deadline::TimeOrStopping(deadline));
+    let chunked = upload_chunks(target_canister, &new_wasm, &mut stop_trying)
+        .await
+        .map_err(|e| format!("Failed to upload chunks: {:?}", e))?;
+    upgrade_canister(
+        target_canister,
+        WasmModule::ChunkedModule(chunked),
+        vec![],
+        UpgradeOptions::default(),
+        &mut stop_trying,
+    )
+    .await
+    .map(|_| ())
+    .map_err(|e| format!("Failed to upgrade canister: {:?}", e))
+}
+
+/// Upgrades the target, letting the library choose between a single-message and a chunked
+/// install, using the target's own chunk store if needed.
+#[update]
+pub async fn try_upgrading_target_auto(
+    target_canister: Principal,
+    new_wasm: Vec<u8>,
+    deadline: u64,
+) -> Result<(), String> {
+    upgrade_canister(
+        target_canister,
+        WasmModule::from_bytes(new_wasm, None),
+        vec![],
+        UpgradeOptions::default(),
+        &mut when_out_of_time_or_stopping(&Deadline::TimeOrStopping(deadline)),
+    )
+    .await
+    .map(|_| ())
+    .map_err(|e| format!("Failed to upgrade canister: {:?}", e))
+}
+
+/// Upgrades the target, returning the progress events of the upgrade along with the result.
+#[update]
+pub async fn try_upgrading_target_with_events(
+    target_canister: Principal,
+    new_wasm: Vec<u8>,
+    deadline: u64,
+) -> (Result<(), String>, Vec<String>) {
+    let mut events = vec![];
+    let result = upgrade_canister_with_observer(
+        target_canister,
+        WasmModule::Bytes(new_wasm),
+        vec![],
+        UpgradeOptions::default(),
+        &mut |event| events.push(format!("{:?}", event)),
+        &mut when_out_of_time_or_stopping(&Deadline::TimeOrStopping(deadline)),
+    )
+    .await
+    .map(|_| ())
+    .map_err(|e| format!("Failed to upgrade canister: {:?}", e));
+    (result, events)
+}
+
+/// Upgrades the target unless it's already running the new module, returning the outcome.
+#[update]
+pub async fn try_upgrading_target_if_outdated(
+    target_canister: Principal,
+    new_wasm: Vec<u8>,
+    deadline: u64,
+) -> Result<String, String> {
+    upgrade_canister(
+        target_canister,
+        WasmModule::Bytes(new_wasm),
+        vec![],
+        UpgradeOptions {
+            skip_if_up_to_date: Some(SkipIfUpToDate::ModuleMatches),
+            ..Default::default()
+        },
+        &mut when_out_of_time_or_stopping(&Deadline::TimeOrStopping(deadline)),
+    )
+    .await
+    .map(|outcome| format!("{:?}", outcome))
+    .map_err(|e| format!("Failed to upgrade canister: {:?}", e))
+}
+
+/// Upgrades the target only if its `total_num_changes` is the expected one.
+#[update]
+pub async fn try_upgrading_target_with_precondition(
+    target_canister: Principal,
+    new_wasm: Vec<u8>,
+    expected_num_changes: u64,
+    deadline: u64,
+) -> Result<(), String> {
+    upgrade_canister(
+        target_canister,
+        WasmModule::Bytes(new_wasm),
+        vec![],
+        UpgradeOptions {
+            precondition: Some(UpgradePrecondition {
+                total_num_changes: Some(expected_num_changes),
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        &mut when_out_of_time_or_stopping(&Deadline::TimeOrStopping(deadline)),
+    )
+    .await
+    .map(|_| ())
+    .map_err(|e| format!("Failed to upgrade canister: {:?}", e))
+}
+
+/// Upgrades the target, restarting it if it can't be stopped.
+#[update]
+pub async fn try_upgrading_target_restarting_on_stop_failure(
+    target_canister: Principal,
+    new_wasm: Vec<u8>,
+    deadline: u64,
+) -> Result<(), String> {
+    upgrade_canister(
+        target_canister,
+        WasmModule::Bytes(new_wasm),
+        vec![],
+        UpgradeOptions {
+            restart_on_stop_failure: true,
+            ..Default::default()
+        },
+        &mut when_out_of_time_or_stopping(&Deadline::TimeOrStopping(deadline)),
+    )
+    .await
+    .map(|_| ())
+    .map_err(|e| format!("Failed to upgrade canister: {:?}", e))
+}
+
+thread_local! {
+    static BLOCKED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Doesn't respond until `release_blocked_calls` is called, letting the caller hold a call
+/// context open.
+#[update]
+pub async fn block_until_released() {
+    BLOCKED.with(|b| b.set(true));
+    while BLOCKED.with(|b| b.get()) {
+        // Yield by making a cheap call, bypassing the chaos policy
+        let _ = ic_cdk::call::Call::bounded_wait(Principal::management_canister(), "canister_info")
+            .with_arg(CanisterInfoArgs {
+                canister_id: ic_cdk::api::canister_self(),
+                num_requested_changes: None,
+            })
+            .await;
+    }
+}
+
+#[update]
+pub fn release_blocked_calls() {
+    BLOCKED.with(|b| b.set(false));
+}
+
+/// Upgrades the target, which holds its upgrade lock. If the target is already being upgraded,
+/// either waits for that upgrade to finish, or fails right away.
+#[update]
+pub async fn try_upgrading_target_locked(
+    target_canister: Principal,
+    new_wasm: Vec<u8>,
+    wait: bool,
+    deadline: u64,
+) -> Result<(), String> {
+    upgrade_canister(
+        target_canister,
+        WasmModule::Bytes(new_wasm),
+        vec![],
+        UpgradeOptions {
+            wait_for_lock: wait,
+            ..Default::default()
+        },
+        &mut when_out_of_time_or_stopping(&Deadline::TimeOrStopping(deadline)),
+    )
+    .await
+    .map(|_| ())
+    .map_err(|e| format!("Failed to upgrade canister: {:?}", e))
+}
+
+/// The targets of the upgrades that currently hold a lock.
+#[query]
+pub fn in_flight_upgrades() -> Vec<Principal> {
+    ic_safe_upgrades::in_flight_upgrades()
+        .into_iter()
+        .map(|upgrade| upgrade.target_id)
+        .collect()
+}
+
+/// Upgrades the target, first checking that the new module exports the given update methods.
+#[update]
+pub async fn try_upgrading_target_with_module_checks(
+    target_canister: Principal,
+    new_wasm: Vec<u8>,
+    required_update_methods: Vec<String>,
+    deadline: u64,
+) -> Result<(), String> {
+    upgrade_canister(
+        target_canister,
+        WasmModule::Bytes(new_wasm),
+        vec![],
+        UpgradeOptions {
+            module_checks: Some(ModuleChecks {
+                required_update_methods,
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        &mut when_out_of_time_or_stopping(&Deadline::TimeOrStopping(deadline)),
+    )
+    .await
+    .map(|_| ())
+    .map_err(|e| format!("Failed to upgrade canister: {:?}", e))
+}
+
+/// Upgrades the target, first checking the new module's interface against the given old one.
+#[update]
+pub async fn try_upgrading_target_with_interface_check(
+    target_canister: Principal,
+    new_wasm: Vec<u8>,
+    old_interface: String,
+    warn_only: bool,
+    deadline: u64,
+) -> Result<(), String> {
+    upgrade_canister(
+        target_canister,
+        WasmModule::Bytes(new_wasm),
+        vec![],
+        UpgradeOptions {
+            interface_check: Some(InterfaceCheck {
+                old_interface: InterfaceSource::Service(old_interface),
+                warn_only,
+            }),
+            ..Default::default()
+        },
+        &mut when_out_of_time_or_stopping(&Deadline::TimeOrStopping(deadline)),
+    )
+    .await
+    .map(|_| ())
+    .map_err(|e| format!("Failed to upgrade canister: {:?}", e))
+}
+
+/// Upgrades the target with the given argument, validating it first.
+#[update]
+pub async fn try_upgrading_target_with_validated_arg(
+    target_canister: Principal,
+    new_wasm: Vec<u8>,
+    arg: Vec<u8>,
+    deadline: u64,
+) -> Result<(), String> {
+    upgrade_canister(
+        target_canister,
+        WasmModule::Bytes(new_wasm),
+        arg,
+        UpgradeOptions {
+            validate_arg: true,
+            ..Default::default()
+        },
+        &mut when_out_of_time_or_stopping(&Deadline::TimeOrStopping(deadline)),
+    )
+    .await
+    .map(|_| ())
+    .map_err(|e| format!("Failed to upgrade canister: {:?}", e))
+}
+
+/// Upgrades the target, relaying the management canister calls through the `relay` method of the
+/// given proxy.
+#[update]
+pub async fn try_upgrading_target_via_proxy(
+    target_canister: Principal,
+    new_wasm: Vec<u8>,
+    proxy_id: Principal,
+    deadline: u64,
+) -> Result<(), String> {
+    upgrade_canister(
+        target_canister,
+        WasmModule::Bytes(new_wasm),
+        vec![],
+        UpgradeOptions {
+            proxy: Some(ManagementProxy {
+                proxy_id,
+                method: "relay".to_string(),
+            }),
+            ..Default::default()
+        },
+        &mut when_out_of_time_or_stopping(&Deadline::TimeOrStopping(deadline)),
+    )
+    .await
+    .map(|_| ())
+    .map_err(|e| format!("Failed to upgrade canister: {:?}", e))
+}
+
+/// Relays a call for a `ManagementProxy`. Relays calls from anyone, which is only fine for tests.
+#[update(manual_reply = true)]
+pub async fn relay(args: RelayArgs) {
+    match ic_cdk::call::Call::unbounded_wait(args.callee, &args.method)
+        .with_raw_args(&args.arg)
+        .await
+    {
+        Ok(response) => ic_cdk::api::msg_reply(response.into_bytes()),
+        Err(e) => ic_cdk::api::msg_reject(format!("Relayed call failed: {:?}", e)),
+    }
+}
+
+/// Deploys the new WASM to the target with the given mode ("install" or "reinstall").
+#[update]
+pub async fn try_deploying_target(
+    target_canister: Principal,
+    new_wasm: Vec<u8>,
+    mode: String,
+    deadline: u64,
+) -> Result<(), String> {
+    let mut stop_trying = when_out_of_time_or_stopping(&Deadline::TimeOrStopping(deadline));
+    let wasm_module = WasmModule::Bytes(new_wasm);
+    match mode.as_str() {
+        "install" => install_canister(target_canister, wasm_module, vec![], &mut stop_trying).await,
+        "reinstall" => {
+            reinstall_canister(target_canister, wasm_module, vec![], &mut stop_trying).await
+        }
+        _ => panic!("Unknown mode: {}", mode),
+    }
+    .map_err(|e| format!("Failed to deploy canister: {:?}", e))
+}
+
+/// Creates a canister controlled by this canister and the given controllers, with the given cycles.
+#[update]
+pub async fn try_creating_canister(
+    controllers: Vec<Principal>,
+    cycles: u128,
+    deadline: u64,
+) -> Result<Principal, String> {
+    let settings = CanisterSettings {
+        controllers: Some([vec![ic_cdk::api::canister_self()], controllers].concat()),
+        ..Default::default()
+    };
+    create_canister(
+        Some(settings),
+        cycles,
+        &mut when_out_of_time_or_stopping(&Deadline::TimeOrStopping(deadline)),
+    )
+    .await
+    .map_err(|e| format!("Failed to create canister: {:?}", e))
+}
+
+/// Applies the given lifecycle operation ("update_settings", "deposit_cycles", "uninstall_code"
+/// or "delete_canister") to the target. Updating the settings sets the freezing threshold to
+/// `amount`, depositing deposits `amount` cycles.
+#[update]
+pub async fn try_lifecycle_operation(
+    target_canister: Principal,
+    operation: String,
+    amount: u128,
+    deadline: u64,
+) -> Result<(), String> {
+    let mut stop_trying = when_out_of_time_or_stopping(&Deadline::TimeOrStopping(deadline));
+    match operation.as_str() {
+        "update_settings" => {
+            let settings = CanisterSettings {
+                freezing_threshold: Some(amount.into()),
+                ..Default::default()
+            };
+            update_settings(target_canister, settings, &mut stop_trying).await
+        }
+        "deposit_cycles" => deposit_cycles(target_canister, amount, &mut stop_trying).await,
+        "uninstall_code" => uninstall_code(target_canister, &mut stop_trying).await,
+        "delete_canister" => delete_canister(target_canister, &mut stop_trying).await,
+        _ => panic!("Unknown operation: {}", operation),
+    }
+    .map_err(|e| format!("Failed to {}: {:?}", operation, e))
+}
+
+/// Adds and removes controllers of the target.
+#[update]
+pub async fn try_changing_controllers(
+    target_canister: Principal,
+    add: Vec<Principal>,
+    remove: Vec<Principal>,
+    allow_removing_self: bool,
+    deadline: u64,
+) -> Result<Vec<Principal>, String> {
+    let change = ControllerChange {
+        add,
+        remove,
+        allow_removing_self,
+    };
+    change_controllers(
+        target_canister,
+        &change,
+        &mut when_out_of_time_or_stopping(&Deadline::TimeOrStopping(deadline)),
+    )
+    .await
+    .map_err(|e| format!("Failed to change controllers: {:?}", e))
+}
+
+thread_local! {
+    static UPGRADE_JOB: RefCell<Option<UpgradeJob>> = const { RefCell::new(None) };
+    static ROLLOUT: RefCell<Option<Rollout>> = const { RefCell::new(None) };
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    stable_save((UPGRADE_JOB.with(|j| j.borrow().clone()),)).expect("Failed to save the upgrade job");
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    let (job,): (Option<UpgradeJob>,) = stable_restore().expect("Failed to restore the upgrade job");
+    UPGRADE_JOB.with(|j| *j.borrow_mut() = job);
+}
+
+async fn run_upgrade_job(deadline: u64) -> Result<(), String> {
+    let mut job = UPGRADE_JOB
+        .with(|j| j.borrow().clone())
+        .ok_or("No upgrade job".to_string())?;
+    job.run(
+        &mut when_out_of_time_or_stopping(&Deadline::TimeOrStopping(deadline)),
+        |job| UPGRADE_JOB.with(|j| *j.borrow_mut() = Some(job.clone())),
+    )
+    .await
+    .map(|_| ())
+    .map_err(|e| format!("Failed to upgrade canister: {:?}", e))
+}
+
+/// Starts a resumable upgrade of the target, persisting its progress across upgrades of this
+/// canister.
+#[update]
+pub async fn start_upgrade_job(
+    target_canister: Principal,
+    new_wasm: Vec<u8>,
+    deadline: u64,
+) -> Result<(), String> {
+    let job = UpgradeJob::new(
+        target_canister,
+        WasmModule::Bytes(new_wasm),
+        vec![],
+        UpgradeOptions::default(),
+    );
+    UPGRADE_JOB.with(|j| *j.borrow_mut() = Some(job));
+    run_upgrade_job(deadline).await
+}
+
+#[update]
+pub async fn resume_upgrade_job(deadline: u64) -> Result<(), String> {
+    run_upgrade_job(deadline).await
+}
+
+#[query]
+pub fn upgrade_job_state() -> Option<String> {
+    UPGRADE_JOB.with(|j| j.borrow().as_ref().map(|job| format!("{:?}", job.state)))
+}
+
+async fn run_rollout(deadline: u64) -> (String, Vec<(Principal, String)>) {
+    let mut rollout = ROLLOUT
+        .with(|r| r.borrow().clone())
+        .expect("No rollout");
+    rollout
+        .run(
+            || when_out_of_time_or_stopping(&Deadline::TimeOrStopping(deadline)),
+            |rollout| ROLLOUT.with(|r| *r.borrow_mut() = Some(rollout.clone())),
+        )
+        .await;
+    let results = rollout
+        .results
+        .iter()
+        .map(|result| (result.target_id, format!("{:?}", result.outcome)))
+        .collect();
+    (format!("{:?}", rollout.status), results)
+}
+
+/// Rolls out the new WASM to the targets, returning the status of the rollout and the outcome
+/// for each target upgraded so far.
+#[update]
+pub async fn start_rollout(
+    targets: Vec<Principal>,
+    new_wasm: Vec<u8>,
+    canary_size: u64,
+    wave_size: u64,
+    max_failure_rate: f64,
+    deadline: u64,
+) -> (String, Vec<(Principal, String)>) {
+    let rollout = Rollout::new(
+        targets,
+        WasmModule::Bytes(new_wasm),
+        vec![],
+        UpgradeOptions::default(),
+        RolloutConfig {
+            canary_size: canary_size as usize,
+            wave_size: wave_size as usize,
+            max_in_flight: 2,
+            max_failure_rate,
+        },
+    );
+    ROLLOUT.with(|r| *r.borrow_mut() = Some(rollout));
+    run_rollout(deadline).await
+}
+
+#[update]
+pub async fn resume_rollout(deadline: u64) -> (String, Vec<(Principal, String)>) {
+    ROLLOUT.with(|r| r.borrow_mut().as_mut().expect("No rollout").resume());
+    run_rollout(deadline).await
+}
+
 struct FailAtStagePolicy {
     stage: UpgradeStage
 }
@@ -44,8 +675,15 @@ impl Policy for FailAtStagePolicy {
         let call_stage  = match call.method {
             "stop_canister" => UpgradeStage::Stopping,
             "canister_info" => UpgradeStage::ObtainingInfo,
-            "install_code" => UpgradeStage::Installing,
+            "install_code" | "install_chunked_code" => UpgradeStage::Installing,
             "start_canister" => UpgradeStage::Starting,
+            // Chunk uploads happen before the upgrade proper
+            "stored_chunks" | "upload_chunk" | "clear_chunk_store" => return Ok(()),
+            // Snapshots and health checks are only used by the rollback flow
+            "list_canister_snapshots" | "take_canister_snapshot" | "load_canister_snapshot"
+            | "delete_canister_snapshot" | "version" => return Ok(()),
+            // Only used to resolve installation attempts with unknown outcomes
+            "canister_status" => return Ok(()),
             _ => panic!("Unknown method: {}", call.method),
         };
         if call_stage == self.stage {
@@ -61,6 +699,104 @@ impl Policy for FailAtStagePolicy {
     }
 }
 
+/// Reports every installation attempt as timed out, without performing it.
+struct UnknownInstallPolicy;
+
+impl Policy for UnknownInstallPolicy {
+    fn allow(&mut self, call: &Call) -> Result<(), CallFailed> {
+        match call.method {
+            "install_code" | "install_chunked_code" => {
+                Err(CallFailed::CallRejected(CallRejected::with_rejection(
+                    RejectCode::SysUnknown as u32,
+                    "Simulate a timed out installation".to_string(),
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn allow_oneway(&mut self, _call: &Call) -> Result<(), Option<OnewayError>> {
+        Ok(())
+    }
+}
+
+/// Reports the first `update_settings` call as timed out, without performing it.
+#[derive(Default)]
+struct UnknownFirstUpdateSettingsPolicy {
+    failed: bool,
+}
+
+impl Policy for UnknownFirstUpdateSettingsPolicy {
+    fn allow(&mut self, call: &Call) -> Result<(), CallFailed> {
+        if call.method == "update_settings" && !self.failed {
+            self.failed = true;
+            return Err(CallFailed::CallRejected(CallRejected::with_rejection(
+                RejectCode::SysUnknown as u32,
+                "Simulate a timed out settings update".to_string(),
+            )));
+        }
+        Ok(())
+    }
+
+    fn allow_oneway(&mut self, _call: &Call) -> Result<(), Option<OnewayError>> {
+        Ok(())
+    }
+}
+
+/// Rejects every attempt to take a snapshot.
+struct DenySnapshotsPolicy;
+
+impl Policy for DenySnapshotsPolicy {
+    fn allow(&mut self, call: &Call) -> Result<(), CallFailed> {
+        match call.method {
+            "take_canister_snapshot" => Err(CallFailed::CallRejected(CallRejected::with_rejection(
+                RejectCode::CanisterReject as u32,
+                "Simulate a rejected snapshot".to_string(),
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    fn allow_oneway(&mut self, _call: &Call) -> Result<(), Option<OnewayError>> {
+        Ok(())
+    }
+}
+
+/// Holds back the first `delete_canister` call, reporting it as timed out, and only performs it
+/// right before the next `delete_canister` call, as if it had been delayed.
+struct DelayedFirstDeletePolicy {
+    target: Principal,
+    held_back: bool,
+    released: bool,
+}
+
+impl Policy for DelayedFirstDeletePolicy {
+    fn allow(&mut self, call: &Call) -> Result<(), CallFailed> {
+        if call.method != "delete_canister" || self.released {
+            return Ok(());
+        }
+        if !self.held_back {
+            self.held_back = true;
+            return Err(CallFailed::CallRejected(CallRejected::with_rejection(
+                RejectCode::SysUnknown as u32,
+                "Simulate a timed out deletion".to_string(),
+            )));
+        }
+        self.released = true;
+        let canister_id = self.target;
+        ic_cdk::futures::spawn_017_compat(async move {
+            let _ = ic_cdk::management_canister::delete_canister(&DeleteCanisterArgs {
+                canister_id,
+            })
+            .await;
+        });
+        Ok(())
+    }
+
+    fn allow_oneway(&mut self, _call: &Call) -> Result<(), Option<OnewayError>> {
+        Ok(())
+    }
+}
 
 #[update]
 pub async fn set_call_chaos_policy(policy: String) {
@@ -69,6 +805,11 @@ pub async fn set_call_chaos_policy(policy: String) {
         "AllowEveryOther" => cc_set_policy(ic_call_chaos::AllowEveryOther::default()),
         "DenyAll" => cc_set_policy(ic_call_chaos::DenyAll::default()),
         "WithProbability" => cc_set_policy(ic_call_chaos::WithProbability::new(0.1, 1337, true)),
+        "UnknownInstall" => cc_set_policy(UnknownInstallPolicy),
+        "DenySnapshots" => cc_set_policy(DenySnapshotsPolicy),
+        "UnknownFirstUpdateSettings" => {
+            cc_set_policy(UnknownFirstUpdateSettingsPolicy::default())
+        }
         _ => panic!("Unknown policy: {}", policy),
     }
 }
@@ -77,3 +818,12 @@ pub async fn set_call_chaos_policy(policy: String) {
 pub async fn set_fail_at_stage_policy(step: u32) {
     cc_set_policy(FailAtStagePolicy::new(step));
 }
+
+#[update]
+pub async fn set_delayed_first_delete_policy(target_canister: Principal) {
+    cc_set_policy(DelayedFirstDeletePolicy {
+        target: target_canister,
+        held_back: false,
+        released: false,
+    });
+}